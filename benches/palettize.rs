@@ -0,0 +1,60 @@
+use analogue_pal_tool::image_handler::ImageHandler;
+use analogue_pal_tool::palette::Palette;
+use analogue_pal_tool::png_helper::{ColorLookup, PngPalette};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Screenshot-like image using all template colors, in blocks like tiles on real screenshot
+fn template_image(width: u32, height: u32) -> DynamicImage {
+    let template = Palette::default();
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        Rgb(template.slot(((x / 8 + y / 8) as usize) % Palette::SLOTS))
+    });
+    DynamicImage::ImageRgb8(image)
+}
+
+fn palettize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("palettize_image");
+    // Original Game Boy screen, scaled up screenshot and some big one
+    for (width, height) in [(160, 144), (640, 576), (1920, 1728)] {
+        let image = template_image(width, height);
+        group.bench_with_input(
+            BenchmarkId::new("lookup", format!("{width}x{height}")),
+            &image,
+            |b, image| {
+                b.iter(|| ImageHandler::palettize_image(Palette::default(), black_box(image)))
+            },
+        );
+        // What palettize_image did before lookup table was used
+        let template: PngPalette = Palette::default().into();
+        group.bench_with_input(
+            BenchmarkId::new("linear_scan", format!("{width}x{height}")),
+            &image,
+            |b, image| {
+                b.iter(|| {
+                    image
+                        .as_rgb8()
+                        .unwrap()
+                        .pixels()
+                        .map(|pixel| {
+                            template
+                                .index_of_with_tolerance(black_box(pixel.0), 8)
+                                .unwrap_or(255) as u8
+                        })
+                        .collect::<Vec<u8>>()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn build_lookup(c: &mut Criterion) {
+    let template: PngPalette = Palette::default().into();
+    c.bench_function("color_lookup_new", |b| {
+        b.iter(|| ColorLookup::new(black_box(&template), 8))
+    });
+}
+
+criterion_group!(benches, palettize, build_lookup);
+criterion_main!(benches);
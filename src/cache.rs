@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+/// Content-hash based cache, so unchanged palette + screenshot combinations are not rendered again.
+///
+/// Layout of cache directory:
+/// * `outputs/<hash of output path>` - key of inputs used to produce that output
+/// * `palettized/<key>.bin` - palettized screenshot: width and height as u32 LE, then palette indexes
+#[derive(Debug)]
+pub struct BuildCache {
+    directory: PathBuf,
+    /// Hashes of files already read during this run, as the same screenshot is used for every palette
+    file_hashes: Mutex<HashMap<String, String>>,
+}
+
+impl BuildCache {
+    pub const DEFAULT_DIRECTORY: &'static str = ".analogue_pal_tool_cache";
+
+    pub fn new(directory: &str) -> Self {
+        let directory = PathBuf::from(directory);
+        for subdirectory in ["outputs", "palettized"] {
+            fs::create_dir_all(directory.join(subdirectory))
+                .unwrap_or_else(|err| panic!("Cannot create cache directory {directory:?}: {err}"));
+        }
+        debug!("Using build cache in {directory:?}");
+        Self {
+            directory,
+            file_hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// SHA-256 of all parts, as hex string
+    pub fn hash(parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            // length prefix, so ["ab", "c"] and ["a", "bc"] have different hashes
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Hash of file contents, computed only once per file during single run
+    pub fn file_hash(&self, file_name: &str) -> Result<String, io::Error> {
+        if let Some(hash) = self.file_hashes.lock().unwrap().get(file_name) {
+            return Ok(hash.clone());
+        }
+        let data = fs::read(file_name)?;
+        let hash = Self::hash(&[&data]);
+        self.file_hashes
+            .lock()
+            .unwrap()
+            .insert(file_name.to_string(), hash.clone());
+        Ok(hash)
+    }
+
+    /// Files may change between runs in watch mode, so their hashes have to be computed again
+    pub fn forget_file_hashes(&self) {
+        self.file_hashes.lock().unwrap().clear();
+    }
+
+    fn output_record(&self, output_file: &str) -> PathBuf {
+        self.directory
+            .join("outputs")
+            .join(Self::hash(&[output_file.as_bytes()]))
+    }
+
+    /// Output exists and was created from inputs with the same key
+    pub fn is_up_to_date(&self, output_file: &str, key: &str) -> bool {
+        Path::new(output_file).exists()
+            && fs::read_to_string(self.output_record(output_file)).is_ok_and(|stored| stored == key)
+    }
+
+    pub fn store_output(&self, output_file: &str, key: &str) {
+        if let Err(err) = fs::write(self.output_record(output_file), key) {
+            warn!("Cannot write cache record for {output_file}: {err}");
+        }
+    }
+
+    fn palettized_file(&self, key: &str) -> PathBuf {
+        self.directory.join("palettized").join(format!("{key}.bin"))
+    }
+
+    /// Palettized screenshot stored under given key: width, height and palette indexes
+    pub fn palettized(&self, key: &str) -> Option<(usize, usize, Vec<u8>)> {
+        let data = fs::read(self.palettized_file(key)).ok()?;
+        if data.len() < 8 {
+            return None;
+        }
+        let width = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let height = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if data.len() != 8 + width * height {
+            warn!("Ignoring corrupted palettized cache entry {key}");
+            return None;
+        }
+        Some((width, height, data[8..].to_vec()))
+    }
+
+    pub fn store_palettized(&self, key: &str, width: usize, height: usize, indexes: &[u8]) {
+        let mut data = Vec::with_capacity(8 + indexes.len());
+        data.extend_from_slice(&(width as u32).to_le_bytes());
+        data.extend_from_slice(&(height as u32).to_le_bytes());
+        data.extend_from_slice(indexes);
+        if let Err(err) = fs::write(self.palettized_file(key), data) {
+            warn!("Cannot write palettized cache entry {key}: {err}");
+        }
+    }
+}
@@ -0,0 +1,119 @@
+/// Tiny built-in 3x5 pixel font for captions drawn into images, so no font files are needed.
+///
+/// Only uppercase letters, digits and some punctuation are available,
+/// lowercase is drawn as uppercase and anything else as '?'
+pub struct Caption;
+
+impl Caption {
+    pub const GLYPH_WIDTH: usize = 3;
+    pub const GLYPH_HEIGHT: usize = 5;
+    /// Space between glyphs
+    const SPACING: usize = 1;
+
+    /// Rows of glyph, top to bottom, leftmost pixel is 0b100
+    fn glyph(c: char) -> [u8; 5] {
+        match c.to_ascii_uppercase() {
+            'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+            'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+            'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+            'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+            'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+            'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+            'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+            'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+            'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+            'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+            'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+            'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+            'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+            'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+            'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+            'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+            'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+            'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+            'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+            'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+            'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+            'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+            'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+            'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+            'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+            'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+            '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+            '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+            '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+            '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+            '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+            '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+            '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+            '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+            '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+            '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+            ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+            '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+            '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+            '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+            ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+            '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+            '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+            ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+            '[' => [0b011, 0b010, 0b010, 0b010, 0b011],
+            ']' => [0b110, 0b010, 0b010, 0b010, 0b110],
+            '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+            '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+            ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+            '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+            '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+            '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+            '$' => [0b011, 0b110, 0b010, 0b011, 0b110],
+            '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+            _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+        }
+    }
+
+    /// Width in pixels of text drawn with given scale
+    pub fn width(text: &str, scale: usize) -> usize {
+        let chars = text.chars().count();
+        (chars * (Self::GLYPH_WIDTH + Self::SPACING)).saturating_sub(Self::SPACING) * scale
+    }
+
+    pub fn height(scale: usize) -> usize {
+        Self::GLYPH_HEIGHT * scale
+    }
+
+    /// Draw text into buffer of `buffer_width` pixels per row, glyphs which would go past
+    /// `max_width` pixels from `x` are not drawn
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw<T: Copy>(
+        buffer: &mut [T],
+        buffer_width: usize,
+        x: usize,
+        y: usize,
+        text: &str,
+        scale: usize,
+        max_width: usize,
+        value: T,
+    ) {
+        let advance = (Self::GLYPH_WIDTH + Self::SPACING) * scale;
+        for (position, c) in text.chars().enumerate() {
+            let glyph_x = x + position * advance;
+            if glyph_x + Self::GLYPH_WIDTH * scale > x + max_width {
+                break;
+            }
+            for (row, bits) in Self::glyph(c).iter().enumerate() {
+                for column in 0..Self::GLYPH_WIDTH {
+                    if bits & (0b100 >> column) == 0 {
+                        continue;
+                    }
+                    for dy in 0..scale {
+                        let start =
+                            (y + row * scale + dy) * buffer_width + glyph_x + column * scale;
+                        if let Some(pixels) = buffer.get_mut(start..start + scale) {
+                            pixels.fill(value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
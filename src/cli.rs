@@ -1,12 +1,22 @@
+use crate::cache::BuildCache;
+use crate::emulator_formats::{Collapse, Format};
 use crate::image_handler::MergeLayout;
-use crate::palette::AsAnsiType;
-use clap::{command, Args, Parser, Subcommand, ValueEnum};
+use crate::naming::NameTemplate;
+use crate::palette::{AsAnsiType, Palette, TemplateStyle};
+use crate::pocket::OnCollision;
+use crate::sgb::SpreadRule;
+use crate::stats::StatsFormat;
+use crate::terminal_preview::GraphicsProtocol;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use itertools::Itertools;
 use log::LevelFilter;
+use serde::{Deserialize, Serialize};
 
 /// We need this so we can implement ValueEnum for foreign type LevelFilter
 ///
 /// So this is basically copy of that original LevelFilter
-#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+#[derive(Debug, Copy, Clone, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MyLevelFilter {
     Off,
     Error,
@@ -41,29 +51,52 @@ impl From<MyLevelFilter> for LevelFilter {
     }
 }
 
+fn parse_slot(name: &str) -> Result<usize, String> {
+    Palette::slot_index(name).ok_or_else(|| {
+        format!(
+            "'{name}' is not palette slot, use slot number 0-{} or one of: {}",
+            Palette::SLOTS - 1,
+            (0..Palette::SLOTS).map(Palette::slot_name).join(", ")
+        )
+    })
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version = env!("GIT_HASH_SHORT"), about)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
-    #[clap(short = 'z', long = "log_level", value_enum, default_value_t)]
-    pub log_level: MyLevelFilter,
+    /// Log level, if not supplied one from project configuration is used, or info
+    #[clap(short = 'z', long = "log_level", value_enum)]
+    pub log_level: Option<MyLevelFilter>,
 }
 
-#[derive(Args, Debug)]
+/// Options not given here are taken from project configuration (analogue_pal_tool.toml), if there is one
+#[derive(Args, Debug, Default)]
 pub struct ColorizeImage {
     /// Name / path to .pal file(s) to read
     ///
     /// Glob patterns may be used e.g: *.pal palettes/**/*.pal
-    #[clap(short = 'p', long = "pal", alias = "palette", required = true)]
+    /// Built-in palettes may be used as well e.g: builtin:dmg builtin:gbc_*
+    #[clap(short = 'p', long = "pal", alias = "palette")]
     pub pal_file_name: Vec<String>,
     /// Name / path to input screenshot(s) .png file(s) to read
     ///
     /// Screenshot(s) MUST be created using palette generated by create-template-pal
     ///
     /// Glob patterns may be used e.g: *.png screenshots/**/*.png
-    #[clap(required = true)]
     pub input_image_files: Vec<String>,
+    /// Template palette screenshots were taken with, see create-template-pal
+    ///
+    /// If not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+    /// Palette screenshots were taken with, to recolor normal screenshots instead of template ones
+    ///
+    /// Recoloring is exact if all 17 colors of this palette are unique,
+    /// otherwise it is best-effort and slots which cannot be told apart are reported
+    #[clap(long, conflicts_with_all = ["template", "gbc_compat"])]
+    pub taken_with: Option<String>,
     /// Name / path to .png file to write
     ///
     /// If multiple input images are provided and --merge is not, then output will be used as a prefix
@@ -74,25 +107,101 @@ pub struct ColorizeImage {
     /// If multiple pal files are provided then pal file path will be included, with '$' replacing '/'
     /// E.g. for 'out.png', and '1.pal' '2.pal' 'directory/3.pal':
     /// out1.pal.png out2.pal.png outdirectory$3.pal.png
-    #[clap(short, long = "output", required = true, verbatim_doc_comment)]
-    pub output_image_file: String,
+    /// For built-in palettes ':' is replaced by '_', e.g. outbuiltin_dmg.png
+    ///
+    /// See --name-template for more control over names
+    #[clap(short, long = "output", verbatim_doc_comment)]
+    pub output_image_file: Option<String>,
+    /// Write outputs into directory mirroring palette directories instead,
+    /// relative to --pal pattern (up to its first wildcard), e.g. DIR/Trashuncle/GBP/foo/screenshot.png
+    /// for packs/Trashuncle/GBP/foo.pal found by 'packs/**/*.pal'
+    ///
+    /// Each palette folder gets index.html with its images, and DIR/index.html links all of them
+    /// (indexes are not written in --watch mode)
+    #[clap(
+        short = 'd',
+        long = "output-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["output_image_file", "name_template"]
+    )]
+    pub output_dir: Option<String>,
+    /// Name output files using template instead, '/' creates directories.
+    ///
+    /// Placeholders:
+    /// {out_dir}       directory of --output, '.' if there is none
+    /// {out_stem}      file name of --output without .png
+    /// {palette_stem}  palette file name without extension, or name of built-in palette
+    /// {palette_name}  palette name from its metadata, palette stem if there is none
+    /// {palette_dir}   directory of palette file, 'builtin' for built-in palettes
+    /// {image_stem}    screenshot file name without extension, 'merged' when merging
+    /// {counter}       number of screenshot: 000, 001...
+    /// {scale}         scale factor
+    /// E.g. '{out_dir}/{palette_stem}/{image_stem}_{scale}x.png'
+    ///
+    /// Characters unsafe in file names are replaced by '_' in placeholder values.
+    /// Nothing is written if any two outputs would have the same name
+    #[clap(long = "name-template", verbatim_doc_comment)]
+    pub name_template: Option<NameTemplate>,
     /// Scale factor to apply for output image, only integer values are supported
     ///
     /// If not supplied no scaling is applied
     #[clap(short = 's', long = "scale")]
     pub scale: Option<u8>,
     /// Merge multiple images into one output image
-    #[clap(short = 'm', long = "merge")]
-    pub merge: bool,
-    /// Merge: maximum columns to use
-    #[clap(short = 'k', long = "columns", default_value_t = 4)]
-    pub max_columns: u8,
-    /// Merge: layout to use while merging
-    #[clap(short = 'l', long = "layout", default_value_t, value_enum)]
-    pub merge_layout: MergeLayout,
+    #[clap(
+        short = 'm',
+        long = "merge",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        overrides_with = "no_merge"
+    )]
+    pub merge: Option<bool>,
+    /// Do not merge, even if project configuration does
+    #[clap(long = "no-merge", overrides_with = "merge")]
+    pub no_merge: bool,
+    /// Merge: maximum columns to use [default: 4]
+    #[clap(short = 'k', long = "columns")]
+    pub max_columns: Option<u8>,
+    /// Merge: layout to use while merging [default: horizontal]
+    #[clap(short = 'l', long = "layout", value_enum)]
+    pub merge_layout: Option<MergeLayout>,
+    /// Merge: palette slot used to fill space around images, e.g. lcd_off, bg_0 or slot number
+    ///
+    /// Images of different sizes are centred in their cells, so padding is visible around them.
+    /// If not supplied unused palette entry is used, which is white
+    #[clap(long = "pad-slot", value_parser = parse_slot)]
+    pub pad_slot: Option<usize>,
+    /// Screenshots are of GB games in GBC compatibility mode, instead of ones taken with template palette
+    ///
+    /// Value is GBC boot ROM palette (button combination held during boot, e.g. up_a, left_b, right_a)
+    /// or .pal file whose bg, obj0 and obj1 layers are used. Use 'auto' to detect boot ROM palette.
+    /// Background and window share palette on GBC, so window slots are not used
+    #[clap(long = "gbc-compat", value_name = "PALETTE")]
+    pub gbc_compat: Option<String>,
+    /// Quantize palette colors to 15-bit BGR555 first, to see how it would look on GBC, SGB or emulators
+    #[clap(short = 'q', long = "quantize-555")]
+    pub quantize_555: bool,
     /// Generate HTML file for image previews
-    #[clap(short = 't', long = "html", default_value_t = false)]
-    pub generate_html: bool,
+    #[clap(
+        short = 't',
+        long = "html",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        overrides_with = "no_html"
+    )]
+    pub generate_html: Option<bool>,
+    /// Do not generate HTML, even if project configuration does
+    #[clap(long = "no-html", overrides_with = "generate_html")]
+    pub no_html: bool,
+    /// Use build cache stored in given directory, so outputs which were already
+    /// rendered from the same palette, screenshots and options are skipped
+    #[clap(long = "cache", num_args = 0..=1, default_missing_value = BuildCache::DEFAULT_DIRECTORY)]
+    pub cache_dir: Option<String>,
+    /// Keep running and re-render outputs when palettes or screenshots change
+    #[clap(short = 'w', long = "watch", default_value_t = false)]
+    pub watch: bool,
 }
 
 #[derive(Args, Debug)]
@@ -100,6 +209,89 @@ pub struct CreateTemplatePal {
     #[clap(short, long = "output", required = true)]
     /// Name / path to .pal file to write
     pub output_pal_file: String,
+    /// Kind of template to generate
+    #[clap(short, long, value_enum, default_value_t)]
+    pub style: TemplateStyle,
+    /// Generate template looking like this palette instead, so it is bearable to play with.
+    ///
+    /// Colors are moved apart until they are at least --min-distance from each other
+    #[clap(short, long, conflicts_with = "style")]
+    pub from: Option<String>,
+    /// Minimal difference (in any of R, G, B channels) between colors of template generated --from palette.
+    ///
+    /// At most 85, so 17 colors still fit
+    #[clap(short, long, default_value_t = 32, value_parser = clap::value_parser!(u8).range(17..=85))]
+    pub min_distance: u8,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportBuiltin {
+    /// Names of built-in palettes to export, with or without 'builtin:' prefix.
+    /// Glob patterns may be used e.g: gbc_*
+    pub names: Vec<String>,
+    /// Export all built-in palettes
+    #[clap(short, long)]
+    pub all: bool,
+    /// List built-in palettes instead of exporting them
+    #[clap(short, long)]
+    pub list: bool,
+    /// Directory to write .pal files to
+    #[clap(short, long = "output", default_value = ".")]
+    pub output_dir: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportSgb {
+    /// File with Super Game Boy PAL01, PAL23, PAL03 or PAL12 packets (raw 16 byte packets or hex text),
+    /// or 4096 bytes dump of 512 SGB system palettes when --system is used
+    pub input_file: String,
+    /// Name / path to .pal file to write
+    #[clap(short, long = "output", required = true)]
+    pub output_pal_file: String,
+    /// System palettes to use from dump, e.g. 12 or 0,1,2,3
+    ///
+    /// They become SGB palettes 0, 1, 2, 3 in given order
+    #[clap(short = 'n', long = "system", value_delimiter = ',')]
+    pub system_palettes: Vec<usize>,
+    /// How 4 colors of SGB palettes are spread across layers of .pal file
+    #[clap(short, long, default_value_t, value_enum)]
+    pub rule: SpreadRule,
+}
+
+#[derive(Args, Debug)]
+pub struct Convert {
+    /// Palette file to convert, or built-in palette e.g. builtin:dmg
+    pub input_file: String,
+    /// Name / path to file to write
+    #[clap(short, long = "output", required = true)]
+    pub output_file: String,
+    /// Format of input file, detected from its extension and contents if not supplied
+    #[clap(short, long, value_enum)]
+    pub from: Option<Format>,
+    /// Format of output file
+    #[clap(short, long, value_enum, default_value = "pocket")]
+    pub to: Format,
+    /// How layers are collapsed for formats with single palette (bgb, sameboy, mister)
+    #[clap(short, long, value_enum, default_value_t)]
+    pub collapse: Collapse,
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertFolder {
+    /// Folder with palettes to convert, subfolders are included
+    pub input_dir: String,
+    /// Folder to write converted palettes to, folder structure of input is kept
+    #[clap(short, long = "output", required = true)]
+    pub output_dir: String,
+    /// Format of input palettes
+    #[clap(short, long, value_enum, default_value = "pocket")]
+    pub from: Format,
+    /// Format of output palettes
+    #[clap(short, long, value_enum, default_value = "mister")]
+    pub to: Format,
+    /// How layers are collapsed for formats with single palette (bgb, sameboy, mister)
+    #[clap(short, long, value_enum, default_value_t)]
+    pub collapse: Collapse,
 }
 
 #[derive(Args, Debug)]
@@ -108,6 +300,282 @@ pub struct Display {
     pub display_type: AsAnsiType,
     /// Name / path to .pal file to read
     pub pal_file_name: String,
+    /// Show palette as it would look on 15-bit hardware (GBC, SGB, many emulators)
+    #[clap(short = 'q', long = "quantize-555")]
+    pub quantize_555: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Pocket {
+    #[command(subcommand)]
+    pub command: PocketCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PocketCommands {
+    /// Copy palettes into palettes folder of SD card (Assets/gb/common/palettes)
+    Install(PocketInstall),
+    /// List palettes installed on SD card
+    #[clap(aliases = ["ls"])]
+    List(PocketList),
+    /// Copy new screenshots from Memories/Screenshots into local folder, grouped by date
+    #[clap(aliases = ["import"])]
+    ImportScreenshots(PocketImportScreenshots),
+    /// Make palettes folder of SD card the same as local curated palette folder
+    Sync(PocketSync),
+}
+
+#[derive(Args, Debug)]
+pub struct PocketInstall {
+    /// Path to mounted SD card
+    pub sd_path: String,
+    /// Name / path to .pal file(s) to install
+    ///
+    /// Glob patterns may be used e.g: *.pal palettes/**/*.pal builtin:*
+    #[clap(required = true)]
+    pub pal_file_names: Vec<String>,
+    /// What to do if different palette with the same file name is already installed
+    #[clap(short, long, value_enum, default_value_t)]
+    pub on_collision: OnCollision,
+}
+
+#[derive(Args, Debug)]
+pub struct PocketList {
+    /// Path to mounted SD card
+    pub sd_path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct PocketImportScreenshots {
+    /// Path to mounted SD card
+    pub sd_path: String,
+    /// Local screenshot library, screenshots go into YYYY-MM-DD subfolders of it
+    #[clap(short = 'o', long = "output", required = true)]
+    pub library_dir: String,
+}
+
+#[derive(Args, Debug)]
+pub struct PocketSync {
+    /// Path to mounted SD card
+    pub sd_path: String,
+    /// Local folder with palettes which should be on SD card, subfolders are included
+    pub source_dir: String,
+    /// Remove installed palettes which are not in source folder
+    #[clap(long)]
+    pub delete: bool,
+    /// Only show what would be done
+    #[clap(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct LibrarySearch {
+    /// Text to look for in palette name, author, license, credit, description and tags
+    pub query: Option<String>,
+    /// Only palettes with this tag, can be repeated to require all of them
+    #[clap(short, long = "tag")]
+    pub tags: Vec<String>,
+    /// Palettes to search, glob patterns may be used e.g: *.pal palettes/**/*.pal
+    ///
+    /// Metadata is read from foo.pal.toml sidecar or palettes.toml manifest in palette folder
+    #[clap(short, long = "palettes", default_value = "builtin:*")]
+    pub pal_file_names: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckScreenshots {
+    /// Name / path to screenshot(s) .png file(s) to check
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Additional palettes screenshots may have been taken with, built-in palettes are always checked
+    ///
+    /// Glob patterns may be used e.g: *.pal palettes/**/*.pal
+    #[clap(short = 'p', long = "pal", alias = "palette")]
+    pub pal_file_names: Vec<String>,
+    /// Template palette screenshots should be taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct AnalyzeLayers {
+    /// Name / path to template screenshot(s) .png file(s) to analyze
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Directory to write layer masks and exploded sheets to
+    #[clap(short, long = "output", required = true)]
+    pub output_dir: String,
+    /// Scale factor to apply for output images
+    #[clap(
+        short = 's',
+        long = "scale",
+        value_parser = clap::value_parser!(u8).range(1..),
+        default_value_t = 1
+    )]
+    pub scale: u8,
+    /// Template palette screenshots were taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ContactSheet {
+    /// Name / path to .pal file(s) to show screenshot in
+    ///
+    /// Glob patterns may be used e.g: *.pal palettes/**/*.pal builtin:*
+    #[clap(short = 'p', long = "pal", alias = "palette", required = true)]
+    pub pal_file_name: Vec<String>,
+    /// Name / path to template screenshot(s) .png file(s), one sheet is written for each
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Directory to write sheets to, named <screenshot>_sheet.png
+    #[clap(short, long = "output", required = true)]
+    pub output_dir: String,
+    /// Scale factor to apply for screenshots, captions are scaled too
+    #[clap(
+        short = 's',
+        long = "scale",
+        value_parser = clap::value_parser!(u8).range(1..),
+        default_value_t = 1
+    )]
+    pub scale: u8,
+    /// Number of tiles in row, by default sheet is about square
+    #[clap(short, long, value_parser = clap::value_parser!(u8).range(1..))]
+    pub columns: Option<u8>,
+    /// Write indexed .png instead of RGB one.
+    ///
+    /// Indexed .png has 256 colors, so sheet holds at most 14 palettes
+    /// and more sheets are written (<screenshot>_sheet_1.png...) when needed
+    #[clap(long)]
+    pub indexed: bool,
+    /// Template palette screenshots were taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Run {
+    /// Names of jobs from project configuration to run, all jobs are run if none are given
+    pub jobs: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Init {
+    /// Overwrite existing configuration
+    #[clap(short, long)]
+    pub force: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Stats {
+    /// Name / path to template screenshot(s) .png file(s)
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Group screenshots by game, captured from file name by {game} placeholder, '*' matches anything
+    ///
+    /// E.g. '{game}_*.png' puts 'Tetris_001.png' into 'Tetris' group.
+    /// Game in foo.png.toml sidecar (game = "Tetris") takes precedence
+    #[clap(short, long)]
+    pub group_by: Option<String>,
+    #[clap(short, long, value_enum, default_value_t)]
+    pub format: StatsFormat,
+    /// File to write statistics to, by default table is logged, JSON and HTML are written to stats.json / stats.html
+    #[clap(short, long = "output")]
+    pub output_file: Option<String>,
+    /// Template palette screenshots were taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Lint {
+    /// Name / path to .pal file(s) to check
+    ///
+    /// Glob patterns may be used e.g: *.pal palettes/**/*.pal builtin:*
+    #[clap(required = true)]
+    pub pal_file_names: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Preview {
+    /// Name / path to .pal file to read
+    #[clap(short = 'p', long = "pal", alias = "palette", required = true)]
+    pub pal_file_name: String,
+    /// Name / path to input screenshot(s) .png file(s) to read
+    ///
+    /// Screenshot(s) MUST be created using palette generated by create-template-pal
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Scale factor to apply for previewed image, only integer values are supported
+    ///
+    /// If not supplied no scaling is applied
+    #[clap(short = 's', long = "scale", value_parser = clap::value_parser!(u8).range(1..))]
+    pub scale: Option<u8>,
+    /// Graphics protocol used to display image in terminal
+    #[clap(short = 'g', long = "protocol", default_value_t, value_enum)]
+    pub protocol: GraphicsProtocol,
+    /// Template palette screenshots were taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Edit {
+    /// Name / path to .pal file to edit, it will be created on save if it does not exist
+    pub pal_file_name: String,
+    /// Name / path to screenshot .png file to preview palette on
+    ///
+    /// Screenshot MUST be created using palette generated by create-template-pal
+    #[clap(short = 'i', long = "image")]
+    pub image_file: Option<String>,
+    /// Scale factor to apply for previewed image, only integer values are supported
+    #[clap(short = 's', long = "scale", value_parser = clap::value_parser!(u8).range(1..))]
+    pub scale: Option<u8>,
+    /// Template palette screenshot was taken with, if not supplied default template is used.
+    ///
+    /// New palette starts as copy of it too
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Serve {
+    /// Name / path to .pal file(s) to serve
+    ///
+    /// Glob patterns may be used e.g: *.pal palettes/**/*.pal
+    #[clap(short = 'p', long = "pal", alias = "palette", required = true)]
+    pub pal_file_name: Vec<String>,
+    /// Name / path to input screenshot(s) .png file(s) to serve
+    ///
+    /// Screenshot(s) MUST be created using palette generated by create-template-pal
+    ///
+    /// Glob patterns may be used e.g: *.png screenshots/**/*.png
+    #[clap(required = true)]
+    pub input_image_files: Vec<String>,
+    /// Port to listen on, server is only available from localhost
+    #[clap(short = 'P', long = "port", default_value_t = 8080)]
+    pub port: u16,
+    /// Default scale factor for colorized images
+    #[clap(short = 's', long = "scale")]
+    pub scale: Option<u8>,
+    /// Directory where new .pal files can be saved from browser
+    ///
+    /// Without it only already existing palettes can be overwritten
+    #[clap(short = 'd', long = "save-dir")]
+    pub save_dir: Option<String>,
+    /// Template palette screenshots were taken with, if not supplied default template is used
+    #[clap(long)]
+    pub template: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -126,4 +594,67 @@ pub enum Commands {
     /// Colorize input screenshot .png file using palette provided and save as new image file
     #[clap(aliases = ["c", "color-image", "color", "colorize"])]
     ColorizeImage(ColorizeImage),
+    /// Colorize input screenshot .png file using palette provided and display it directly in terminal.
+    ///
+    /// Uses Kitty, iTerm2 or Sixel graphics protocol if terminal supports it,
+    /// otherwise falls back to Unicode half-blocks which require 24-bit color support.
+    #[clap(aliases = ["p", "view"])]
+    Preview(Preview),
+    /// Edit palette in interactive terminal UI, with live preview of template screenshot.
+    /// Requires 24-bit color support in terminal.
+    #[clap(aliases = ["e"])]
+    Edit(Edit),
+    /// Start local HTTP server with palette gallery, browser palette editor and REST API.
+    ///
+    /// API: GET /palettes, GET /screenshots, POST /palettes {path, colors},
+    /// POST /colorize {screenshot, palette or colors, scale} (also as GET with query parameters)
+    #[clap(aliases = ["server", "web"])]
+    Serve(Serve),
+    /// Export palettes shipped with this tool as .pal files, or list them.
+    ///
+    /// Built-in palettes can also be used directly as builtin:<name> wherever .pal file is accepted.
+    #[clap(aliases = ["builtin", "builtins"])]
+    ExportBuiltin(ExportBuiltin),
+    /// Import Super Game Boy palette from palette packets or system palettes dump, and save it as .pal file.
+    ///
+    /// SGB colors are 15-bit, they are converted to 24-bit colors used by .pal files.
+    #[clap(aliases = ["sgb"])]
+    ImportSgb(ImportSgb),
+    /// Check palettes for problems, e.g. different slots which are the same color in 15-bit BGR555.
+    ///
+    /// Exits with non-zero code if any problem was found.
+    Lint(Lint),
+    /// Convert palette between Pocket .pal and emulator palette formats.
+    ///
+    /// Emulators have less layers than Pocket (background, window, obj0, obj1, lcd_off),
+    /// see --to values for how they are folded.
+    Convert(Convert),
+    /// Convert whole folder of palettes, e.g. Pocket palettes for MiSTer
+    ConvertFolder(ConvertFolder),
+    /// Check if screenshots were taken with template palette, or with which known palette otherwise.
+    ///
+    /// Exits with non-zero code if any screenshot is not template screenshot.
+    #[clap(aliases = ["check"])]
+    CheckScreenshots(CheckScreenshots),
+    /// Show which layers (background, objects, window) and slots game uses, based on template screenshots.
+    ///
+    /// Writes black and white mask of each layer, and exploded sheet with each layer shown separately.
+    #[clap(aliases = ["layers"])]
+    AnalyzeLayers(AnalyzeLayers),
+    /// Show how often each palette slot appears in template screenshots, optionally per game.
+    ///
+    /// Also lists slots which are never used, and which slots are most often next to each other.
+    Stats(Stats),
+    /// Show screenshot in every palette on one image, each tile captioned with palette name
+    #[clap(aliases = ["sheet"])]
+    ContactSheet(ContactSheet),
+    /// Search palettes by metadata (author, tags...) and list them with their colors
+    #[clap(aliases = ["lib", "search"])]
+    Library(LibrarySearch),
+    /// Run colorize jobs defined in project configuration (analogue_pal_tool.toml)
+    Run(Run),
+    /// Write project configuration analogue_pal_tool.toml into current directory, to be edited
+    Init(Init),
+    /// Work with mounted Analogue Pocket SD card: install and sync palettes, import screenshots
+    Pocket(Pocket),
 }
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cli::{ColorizeImage, MyLevelFilter};
+use crate::image_handler::MergeLayout;
+use crate::library::Library;
+use crate::naming;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot parse {0:?}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid name_template in {0:?}: {1}")]
+    NameTemplate(PathBuf, naming::Error),
+    #[error("output_dir cannot be used with output or name_template (given on command line or in {0:?})")]
+    OutputConflict(PathBuf),
+    #[error("There is no job '{0}' in {1:?}, available jobs: {2}")]
+    UnknownJob(String, PathBuf, String),
+    #[error("{0:?} already exists, use --force to overwrite it")]
+    AlreadyExists(PathBuf),
+    #[error("Error while accessing project configuration: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// Colorize settings, same as options of colorize-image with the same names
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColorizeConfig {
+    #[serde(default)]
+    pub palettes: Vec<String>,
+    #[serde(default)]
+    pub screenshots: Vec<String>,
+    pub output: Option<String>,
+    pub output_dir: Option<String>,
+    pub name_template: Option<String>,
+    pub template: Option<String>,
+    pub scale: Option<u8>,
+    pub merge: Option<bool>,
+    pub layout: Option<MergeLayout>,
+    pub columns: Option<u8>,
+    pub html: Option<bool>,
+    pub cache: Option<String>,
+}
+
+/// Project configuration `analogue_pal_tool.toml`, so long command lines do not have to be repeated.
+///
+/// Found in current directory or any of its parents. Paths in it are relative to its directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Where config was found, relative to current directory when possible
+    #[serde(skip)]
+    pub file: PathBuf,
+    pub log_level: Option<MyLevelFilter>,
+    /// Used by colorize-image, and by jobs for values they do not set
+    #[serde(default)]
+    pub colorize: ColorizeConfig,
+    /// Named colorize runs for `run` command
+    #[serde(default)]
+    pub jobs: BTreeMap<String, ColorizeConfig>,
+}
+
+impl ColorizeConfig {
+    /// Values from self, missing ones taken from other
+    fn or(self, other: &Self) -> Self {
+        let other = other.clone();
+        // Any kind of output replaces both kinds from other, output_dir also replaces name_template
+        let name_template = match self.output_dir.is_some() {
+            true => self.name_template,
+            false => self.name_template.or(other.name_template),
+        };
+        let (output, output_dir) = match self.output.is_some() || self.output_dir.is_some() {
+            true => (self.output, self.output_dir),
+            false => (other.output, other.output_dir),
+        };
+        Self {
+            palettes: if self.palettes.is_empty() {
+                other.palettes
+            } else {
+                self.palettes
+            },
+            screenshots: if self.screenshots.is_empty() {
+                other.screenshots
+            } else {
+                self.screenshots
+            },
+            output,
+            output_dir,
+            name_template,
+            template: self.template.or(other.template),
+            scale: self.scale.or(other.scale),
+            merge: self.merge.or(other.merge),
+            layout: self.layout.or(other.layout),
+            columns: self.columns.or(other.columns),
+            html: self.html.or(other.html),
+            cache: self.cache.or(other.cache),
+        }
+    }
+}
+
+impl ProjectConfig {
+    pub const FILE_NAME: &'static str = "analogue_pal_tool.toml";
+
+    /// Written by `init`, everything optional is commented out
+    const SCAFFOLD: &'static str = r#"# Project configuration of analogue_pal_tool, found in this directory or any of its subdirectories.
+# Paths are relative to this file, command line options take precedence over values here.
+
+# off, error, warn, info, debug or trace
+log_level = "info"
+
+# Used by colorize-image, and by jobs for values they do not set
+[colorize]
+palettes = ["palettes/**/*.pal"]
+screenshots = ["screenshots/*.png"]
+output = "out/preview.png"
+scale = 2
+# template = "my_template.pal"
+# name_template = "{out_dir}/{palette_stem}/{image_stem}_{scale}x.png"
+# merge = true
+# layout = "horizontal"
+# columns = 4
+# html = true
+# cache = ".apt_cache"
+
+# Run with: analogue_pal_tool run preview
+[jobs.preview]
+name_template = "{out_dir}/{out_stem}_{palette_stem}.png"
+merge = true
+columns = 4
+html = true
+
+# Run with: analogue_pal_tool run gallery
+[jobs.gallery]
+output_dir = "gallery"
+"#;
+
+    /// Config in current directory or closest parent which has one
+    pub fn discover() -> Result<Option<Self>, Error> {
+        let current_dir = std::env::current_dir()?;
+        for (levels, dir) in current_dir.ancestors().enumerate() {
+            if dir.join(Self::FILE_NAME).is_file() {
+                let base: PathBuf = (0..levels).map(|_| "..").collect();
+                return Self::load(&base.join(Self::FILE_NAME)).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn load(file: &Path) -> Result<Self, Error> {
+        debug!("Reading project configuration {:?}", file);
+        let text = fs::read_to_string(file)?;
+        let mut config: Self =
+            toml::from_str(&text).map_err(|err| Error::Parse(file.to_path_buf(), err))?;
+        config.file = file.to_path_buf();
+        Ok(config)
+    }
+
+    /// Write scaffold config into current directory
+    pub fn init(force: bool) -> Result<PathBuf, Error> {
+        let file = PathBuf::from(Self::FILE_NAME);
+        if file.exists() && !force {
+            return Err(Error::AlreadyExists(file));
+        }
+        fs::write(&file, Self::SCAFFOLD)?;
+        Ok(file)
+    }
+
+    /// Path from config made relative to current directory, built-in palettes are left as they are
+    fn resolve(&self, path: &str) -> String {
+        match self.file.parent() {
+            Some(dir) if !Library::is_builtin(path) && Path::new(path).is_relative() => {
+                dir.join(path).to_string_lossy().to_string()
+            }
+            _ => path.to_string(),
+        }
+    }
+
+    fn resolve_all(&self, paths: &[String]) -> Vec<String> {
+        paths.iter().map(|path| self.resolve(path)).collect()
+    }
+
+    /// Fill options not given on command line, flags set there (e.g. --no-merge) are kept too.
+    ///
+    /// Error if output options given on command line conflict with ones from config
+    pub fn apply(&self, config: &ColorizeConfig, args: &mut ColorizeImage) -> Result<(), Error> {
+        if args.pal_file_name.is_empty() {
+            args.pal_file_name = self.resolve_all(&config.palettes);
+        }
+        if args.input_image_files.is_empty() {
+            args.input_image_files = self.resolve_all(&config.screenshots);
+        }
+        // Output given on command line replaces all kinds of output from config
+        if args.output_image_file.is_none() && args.output_dir.is_none() {
+            args.output_dir = config.output_dir.as_deref().map(|path| self.resolve(path));
+            args.output_image_file = config.output.as_deref().map(|path| self.resolve(path));
+            if args.name_template.is_none() {
+                args.name_template = config
+                    .name_template
+                    .as_deref()
+                    .map(|template| template.parse())
+                    .transpose()
+                    .map_err(|err| Error::NameTemplate(self.file.clone(), err))?;
+            }
+        }
+        // Same conflict as checked by clap, but with output from config
+        if args.output_dir.is_some()
+            && (args.output_image_file.is_some() || args.name_template.is_some())
+        {
+            return Err(Error::OutputConflict(self.file.clone()));
+        }
+        if args.template.is_none() && args.taken_with.is_none() && args.gbc_compat.is_none() {
+            args.template = config.template.as_deref().map(|path| self.resolve(path));
+        }
+        args.scale = args.scale.or(config.scale);
+        args.merge = args
+            .merge
+            .or(args.no_merge.then_some(false))
+            .or(config.merge);
+        args.merge_layout = args.merge_layout.or(config.layout);
+        args.max_columns = args.max_columns.or(config.columns);
+        args.generate_html = args
+            .generate_html
+            .or(args.no_html.then_some(false))
+            .or(config.html);
+        if args.cache_dir.is_none() {
+            args.cache_dir = config.cache.as_deref().map(|path| self.resolve(path));
+        }
+        Ok(())
+    }
+
+    /// Settings of colorize-image
+    pub fn colorize_args(&self, args: &mut ColorizeImage) -> Result<(), Error> {
+        self.apply(&self.colorize, args)
+    }
+
+    /// Settings of job, with missing ones taken from `[colorize]`
+    pub fn job_args(&self, name: &str) -> Result<ColorizeImage, Error> {
+        let job = self.jobs.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.jobs.keys().map(String::as_str).collect();
+            Error::UnknownJob(name.to_string(), self.file.clone(), names.join(", "))
+        })?;
+        let mut args = ColorizeImage::default();
+        self.apply(&job.clone().or(&self.colorize), &mut args)?;
+        Ok(args)
+    }
+}
@@ -0,0 +1,232 @@
+use std::fs;
+use std::path::Path;
+
+use image::RgbImage;
+use log::{info, warn};
+
+use crate::caption::Caption;
+use crate::image_handler::{self, ColorMode, ImageHandler};
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+use crate::naming::{self, NameTemplate};
+use crate::palette::{Color, Palette};
+use crate::png_helper::{PngHelper, PngPalette};
+
+/// One screenshot rendered in many palettes, tiled on single image with palette name captions.
+///
+/// Each tile has its own palette, so sheet is built from global color indexes:
+/// tile `n` uses indexes `n * 17 .. n * 17 + 16`, then it is saved as RGB,
+/// or as indexed .png if all indexes fit in 256 color palette
+pub struct ContactSheet {
+    /// Caption and palette of each tile
+    tiles: Vec<(String, Palette)>,
+}
+
+impl ContactSheet {
+    /// Around and between tiles, before scaling
+    const GAP: usize = 4;
+    const BACKGROUND: Color = [0x40, 0x40, 0x50];
+    const TEXT: Color = [0xEE, 0xEE, 0xEE];
+    /// Indexed sheet needs two more indexes, for background and captions
+    pub const MAX_INDEXED_PALETTES: usize = (256 - 2) / Palette::SLOTS;
+
+    /// Tiles for palette files, captioned with name from metadata or file name
+    pub fn new(pal_files: &[String]) -> Self {
+        let tiles = pal_files
+            .iter()
+            .filter_map(|pal_file| match Palette::load(pal_file) {
+                Ok(palette) => {
+                    let caption = PaletteMetadata::load(pal_file).name.unwrap_or_else(|| {
+                        let name = pal_file.strip_prefix(Library::PREFIX).unwrap_or(pal_file);
+                        Path::new(name)
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().to_string())
+                            .unwrap_or_else(|| name.to_string())
+                    });
+                    Some((caption, palette))
+                }
+                Err(err) => {
+                    warn!("Skipping palette {pal_file}: {err}");
+                    None
+                }
+            })
+            .collect();
+        Self { tiles }
+    }
+
+    /// Colors of global indexes, background and text are last two
+    fn colors(tiles: &[(String, Palette)]) -> Vec<Color> {
+        let mut colors: Vec<Color> = tiles
+            .iter()
+            .flat_map(|(_, palette)| (0..Palette::SLOTS).map(|slot| palette.slot(slot)))
+            .collect();
+        colors.extend([Self::BACKGROUND, Self::TEXT]);
+        colors
+    }
+
+    /// Tile screenshot (already palettized and scaled) in each palette, returns width, height
+    /// and global color indexes, see [`Self::colors`]
+    fn build(
+        tiles: &[(String, Palette)],
+        indexes: &[u8],
+        width: usize,
+        height: usize,
+        scale: usize,
+        columns: usize,
+    ) -> (usize, usize, Vec<u16>) {
+        let background = (tiles.len() * Palette::SLOTS) as u16;
+        let text = background + 1;
+        let gap = Self::GAP * scale;
+        let caption_scale = scale.max(2);
+        let caption_height = Caption::height(caption_scale) + gap;
+        let columns = columns.clamp(1, tiles.len().max(1));
+        let rows = tiles.len().div_ceil(columns);
+        let (cell_width, cell_height) = (width, caption_height + height);
+        let sheet_width = columns * cell_width + (columns + 1) * gap;
+        let sheet_height = rows * cell_height + (rows + 1) * gap;
+        let mut sheet = vec![background; sheet_width * sheet_height];
+        for (number, (caption, _)) in tiles.iter().enumerate() {
+            let (row, column) = (number / columns, number % columns);
+            let x = gap + column * (cell_width + gap);
+            let y = gap + row * (cell_height + gap);
+            Caption::draw(
+                &mut sheet,
+                sheet_width,
+                x,
+                y,
+                caption,
+                caption_scale,
+                cell_width,
+                text,
+            );
+            let base = (number * Palette::SLOTS) as u16;
+            for (source_row, pixels) in indexes.chunks_exact(width).enumerate() {
+                let start = (y + caption_height + source_row) * sheet_width + x;
+                for (target, index) in sheet[start..start + width].iter_mut().zip(pixels) {
+                    *target = match *index as usize {
+                        index if index < Palette::SLOTS => base + index as u16,
+                        _ => background,
+                    };
+                }
+            }
+        }
+        (sheet_width, sheet_height, sheet)
+    }
+
+    /// Write contact sheet(s) of screenshot, returns written files.
+    ///
+    /// Indexed sheets hold at most [`Self::MAX_INDEXED_PALETTES`] palettes,
+    /// so there may be more of them, numbered `_1`, `_2`...
+    pub fn write(
+        &self,
+        template: &Palette,
+        input_image: &str,
+        output_file: &str,
+        scale: usize,
+        columns: Option<usize>,
+        indexed: bool,
+    ) -> Result<Vec<String>, image_handler::Error> {
+        let (width, height, indexes) =
+            ImageHandler::palettize_file(template, input_image, &ColorMode::Template, None, None)?;
+        let indexes = ImageHandler::scale_paletted_image(&indexes, width, height, scale);
+        let (width, height) = (width * scale, height * scale);
+        let chunk_size = if indexed {
+            Self::MAX_INDEXED_PALETTES
+        } else {
+            self.tiles.len().max(1)
+        };
+        let chunks: Vec<&[(String, Palette)]> = self.tiles.chunks(chunk_size).collect();
+        Ok(chunks
+            .iter()
+            .enumerate()
+            .map(|(number, tiles)| {
+                let columns =
+                    columns.unwrap_or_else(|| (tiles.len() as f64).sqrt().ceil() as usize);
+                let (sheet_width, sheet_height, sheet) =
+                    Self::build(tiles, &indexes, width, height, scale, columns);
+                let colors = Self::colors(tiles);
+                let file_name = if chunks.len() > 1 {
+                    ImageHandler::with_suffix(output_file, &format!("_{}", number + 1))
+                } else {
+                    output_file.to_string()
+                };
+                info!(
+                    "Saving contact sheet {file_name} with {} palette(s)",
+                    tiles.len()
+                );
+                if indexed {
+                    let mut pal = PngPalette::new();
+                    colors.iter().for_each(|color| {
+                        pal.push(*color);
+                    });
+                    let pal: [u8; 256 * 3] = pal.into();
+                    let data: Vec<u8> = sheet.iter().map(|index| *index as u8).collect();
+                    PngHelper::save(
+                        &file_name,
+                        sheet_width as u32,
+                        sheet_height as u32,
+                        &pal,
+                        &data,
+                    );
+                } else {
+                    let data: Vec<u8> = sheet
+                        .iter()
+                        .flat_map(|index| colors[*index as usize])
+                        .collect();
+                    RgbImage::from_raw(sheet_width as u32, sheet_height as u32, data)
+                        .expect("Sheet has wrong size")
+                        .save(&file_name)
+                        .unwrap_or_else(|err| panic!("Cannot save {file_name}: {err}"));
+                }
+                file_name
+            })
+            .collect())
+    }
+
+    /// Contact sheet for each screenshot, named `<screenshot name>_sheet.png` in output directory.
+    ///
+    /// Screenshots with the same name (from different folders) are reported as error
+    /// before anything is written, screenshots which cannot be read are skipped with warning
+    pub fn write_all(
+        &self,
+        template: &Palette,
+        input_images: &[String],
+        output_dir: &str,
+        scale: usize,
+        columns: Option<usize>,
+        indexed: bool,
+    ) -> Result<Vec<String>, naming::Error> {
+        let output_files: Vec<String> = input_images
+            .iter()
+            .map(|input_image| {
+                let stem = Path::new(input_image)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                Path::new(output_dir)
+                    .join(format!("{stem}_sheet.png"))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        NameTemplate::check_collisions(
+            output_files
+                .iter()
+                .map(String::as_str)
+                .zip(input_images.iter().cloned()),
+        )?;
+        fs::create_dir_all(output_dir)
+            .unwrap_or_else(|err| panic!("Cannot create directory {output_dir}: {err}"));
+        Ok(input_images
+            .iter()
+            .zip(&output_files)
+            .flat_map(|(input_image, output_file)| {
+                self.write(template, input_image, output_file, scale, columns, indexed)
+                    .unwrap_or_else(|err| {
+                        warn!("{err}, skipping it");
+                        Vec::new()
+                    })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::{self, Stdout, Write};
+use std::path::Path;
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+    KeyModifiers,
+};
+use crossterm::style::{Attribute, Color as TermColor, Print, SetAttribute, SetBackgroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use crate::image_handler::ImageHandler;
+use crate::library::Library;
+use crate::palette::{Color, ColorExt, Palette};
+use crate::png_helper::PngPalette;
+use crate::terminal_preview::TerminalPreview;
+
+/// Color component currently adjusted by keys
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Channel {
+    Red,
+    Green,
+    Blue,
+    Hue,
+    Saturation,
+    Lightness,
+}
+
+impl Channel {
+    const ALL: [Channel; 6] = [
+        Channel::Red,
+        Channel::Green,
+        Channel::Blue,
+        Channel::Hue,
+        Channel::Saturation,
+        Channel::Lightness,
+    ];
+
+    fn position(&self) -> usize {
+        Self::ALL.iter().position(|c| c == self).unwrap()
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Channel::Red => "R",
+            Channel::Green => "G",
+            Channel::Blue => "B",
+            Channel::Hue => "H",
+            Channel::Saturation => "S",
+            Channel::Lightness => "L",
+        }
+    }
+
+    /// Change color by `steps`, for RGB step is 1, for hue 1 degree and for S / L 1 percent.
+    ///
+    /// HSL step which rounds back to the same RGB is repeated until color changes,
+    /// so every key press does something (unless color is already at the limit)
+    fn adjust(&self, color: Color, steps: i32) -> Color {
+        let rgb = |i: usize| {
+            let mut color = color;
+            color[i] = (color[i] as i32 + steps).clamp(0, 255) as u8;
+            color
+        };
+        let hsl = |i: usize, step: f32, limit: i32| {
+            let start = color.to_hsl();
+            (1..=limit)
+                .map(|repeat| {
+                    let mut hsl = start;
+                    hsl[i] += (steps * repeat) as f32 * step;
+                    Color::from_hsl(hsl)
+                })
+                .find(|adjusted| *adjusted != color)
+                .unwrap_or(color)
+        };
+        match self {
+            Channel::Red => rgb(0),
+            Channel::Green => rgb(1),
+            Channel::Blue => rgb(2),
+            Channel::Hue => hsl(0, 1.0, 360),
+            Channel::Saturation => hsl(1, 0.01, 100),
+            Channel::Lightness => hsl(2, 0.01, 100),
+        }
+    }
+}
+
+/// Restores terminal on drop, so it is not left in raw mode even if we panic
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new(stdout: &mut Stdout) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, EnableBracketedPaste, Hide)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(
+            io::stdout(),
+            Show,
+            DisableBracketedPaste,
+            LeaveAlternateScreen
+        );
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Full screen terminal palette editor
+pub struct Editor {
+    palette: Palette,
+    file_name: String,
+    /// Palettized template screenshot: width, height and palette indexes
+    preview: Option<(usize, usize, Vec<u8>)>,
+    selected: usize,
+    channel: Channel,
+    undo: Vec<Palette>,
+    redo: Vec<Palette>,
+    hex_input: Option<String>,
+    status: String,
+    dirty: bool,
+}
+
+impl Editor {
+    /// Width of palette slots list, preview is drawn right of it
+    const LIST_WIDTH: u16 = 56;
+    const BIG_STEP: i32 = 16;
+
+    const HELP: &'static str = "↑↓ slot  ←→ channel  +/- adjust  PgUp/PgDn adjust more  # hex  \
+                                u undo  U redo  s save  q quit";
+
+    /// Screenshot is expected to be taken with template, which is also starting palette of new file
+    pub fn new(
+        pal_file: &str,
+        image_file: Option<&str>,
+        scale: Option<u8>,
+        template: Palette,
+    ) -> Self {
+        let (palette, status) = if Path::new(pal_file).exists() {
+            let palette =
+                Palette::load(pal_file).unwrap_or_else(|err| panic!("Cannot load palette: {err}"));
+            (palette, format!("Loaded {pal_file}"))
+        } else {
+            (
+                template.clone(),
+                format!("{pal_file} does not exist, starting with template palette"),
+            )
+        };
+        let preview = image_file.map(|image_file| {
+            let image = ImageHandler::load_image(image_file);
+            let (width, height) = (image.width() as usize, image.height() as usize);
+            let scale = scale.unwrap_or(1) as usize;
+            let unscaled = ImageHandler::palettize_image(template, &image);
+            let scaled = ImageHandler::scale_paletted_image(&unscaled, width, height, scale);
+            (width * scale, height * scale, scaled)
+        });
+        Self {
+            palette,
+            file_name: pal_file.to_string(),
+            preview,
+            selected: 0,
+            channel: Channel::Red,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            hex_input: None,
+            status,
+            dirty: false,
+        }
+    }
+
+    /// Run editor until user quits
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        let _guard = TerminalGuard::new(&mut stdout)?;
+        loop {
+            self.draw(&mut stdout)?;
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Release && !self.handle_key(key) => {
+                    break;
+                }
+                Event::Paste(text) => self.paste(&text),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn modify(&mut self, color: Color) {
+        if self.palette.slot(self.selected) == color {
+            return;
+        }
+        self.undo.push(self.palette.clone());
+        self.redo.clear();
+        self.palette.set_slot(self.selected, color);
+        self.dirty = true;
+    }
+
+    fn adjust(&mut self, steps: i32) {
+        let color = self.channel.adjust(self.palette.slot(self.selected), steps);
+        self.modify(color);
+    }
+
+    /// Pasted text may contain multiple hex values, these are applied to consecutive slots
+    fn paste(&mut self, text: &str) {
+        let colors: Vec<Color> = text
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+            .filter_map(Color::from_hex)
+            .collect();
+        if colors.is_empty() {
+            self.status = format!("Cannot parse '{}' as hex color(s)", text.trim());
+            return;
+        }
+        self.undo.push(self.palette.clone());
+        self.redo.clear();
+        colors
+            .iter()
+            .zip(self.selected..Palette::SLOTS)
+            .for_each(|(color, slot)| self.palette.set_slot(slot, *color));
+        self.dirty = true;
+        self.status = format!(
+            "Pasted {} color(s)",
+            colors.len().min(Palette::SLOTS - self.selected)
+        );
+    }
+
+    /// Returns false if editor should quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if let Some(input) = &mut self.hex_input {
+            match key.code {
+                KeyCode::Enter => {
+                    let input = self.hex_input.take().unwrap();
+                    self.paste(&input);
+                }
+                KeyCode::Esc => self.hex_input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) if c.is_ascii_hexdigit() || c == '#' => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+        let quit_requested = self.status.starts_with("Unsaved changes");
+        self.status.clear();
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char('q') | KeyCode::Esc => {
+                if !self.dirty || quit_requested {
+                    return false;
+                }
+                self.status = "Unsaved changes, press q again to quit without saving".to_string();
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = (self.selected + Palette::SLOTS - 1) % Palette::SLOTS
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1) % Palette::SLOTS
+            }
+            KeyCode::Left | KeyCode::Char('h') => {
+                let position =
+                    (self.channel.position() + Channel::ALL.len() - 1) % Channel::ALL.len();
+                self.channel = Channel::ALL[position];
+            }
+            KeyCode::Right | KeyCode::Char('l') => {
+                let position = (self.channel.position() + 1) % Channel::ALL.len();
+                self.channel = Channel::ALL[position];
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Char(']') => self.adjust(1),
+            KeyCode::Char('-') | KeyCode::Char('[') => self.adjust(-1),
+            KeyCode::PageUp | KeyCode::Char('}') => self.adjust(Self::BIG_STEP),
+            KeyCode::PageDown | KeyCode::Char('{') => self.adjust(-Self::BIG_STEP),
+            KeyCode::Char('#') => self.hex_input = Some(String::new()),
+            KeyCode::Char('u') => {
+                if let Some(palette) = self.undo.pop() {
+                    self.redo
+                        .push(std::mem::replace(&mut self.palette, palette));
+                    self.dirty = true;
+                } else {
+                    self.status = "Nothing to undo".to_string();
+                }
+            }
+            KeyCode::Char('U') | KeyCode::Char('r') => {
+                if let Some(palette) = self.redo.pop() {
+                    self.undo
+                        .push(std::mem::replace(&mut self.palette, palette));
+                    self.dirty = true;
+                } else {
+                    self.status = "Nothing to redo".to_string();
+                }
+            }
+            KeyCode::Char('s') if Library::is_builtin(&self.file_name) => {
+                self.status =
+                    "Built-in palettes are read-only, export it with export-builtin first"
+                        .to_string();
+            }
+            KeyCode::Char('s') => {
+                self.palette.save(&self.file_name);
+                self.dirty = false;
+                // Not logged, as logger writes to stdout which is used by editor
+                self.status = format!("Saved {}", self.file_name);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, stdout: &mut Stdout) -> io::Result<()> {
+        queue!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+        let title = format!(
+            "{}{}",
+            self.file_name,
+            if self.dirty { " [modified]" } else { "" }
+        );
+        queue!(
+            stdout,
+            SetAttribute(Attribute::Bold),
+            Print(title),
+            SetAttribute(Attribute::Reset)
+        )?;
+
+        // Same grouping as `AsAnsiVec for Palette`
+        let layers = [
+            ("-- Background --", 0..4),
+            ("-- Object 0 --", 4..8),
+            ("-- Object 1 --", 8..12),
+            ("-- Window --", 12..16),
+            ("-- LCD Off --", 16..17),
+        ];
+        let mut row = 2_u16;
+        for (header, slots) in layers {
+            queue!(stdout, MoveTo(0, row), Print(header))?;
+            row += 1;
+            for slot in slots {
+                self.draw_slot(stdout, slot, row)?;
+                row += 1;
+            }
+        }
+
+        if let Some(input) = &self.hex_input {
+            queue!(
+                stdout,
+                MoveTo(0, row + 1),
+                Print(format!("Hex color: {input}_"))
+            )?;
+        } else {
+            queue!(stdout, MoveTo(0, row + 1), Print(&self.status))?;
+        }
+        queue!(stdout, MoveTo(0, row + 2), Print(Self::HELP))?;
+
+        if let Some((width, height, data)) = &self.preview {
+            let pal: PngPalette = self.palette.clone().into();
+            let pal: [u8; 256 * 3] = pal.into();
+            let lines = TerminalPreview::half_blocks(*width, *height, &pal, data);
+            for (i, line) in lines.iter().enumerate() {
+                queue!(stdout, MoveTo(Self::LIST_WIDTH, 2 + i as u16), Print(line))?;
+            }
+        }
+        stdout.flush()
+    }
+
+    fn draw_slot(&self, stdout: &mut Stdout, slot: usize, row: u16) -> io::Result<()> {
+        let color = self.palette.slot(slot);
+        let selected = slot == self.selected;
+        let [h, s, l] = color.to_hsl();
+        queue!(
+            stdout,
+            MoveTo(0, row),
+            Print(if selected { "> " } else { "  " }),
+            SetBackgroundColor(TermColor::Rgb {
+                r: color[0],
+                g: color[1],
+                b: color[2]
+            }),
+            Print("    "),
+            SetBackgroundColor(TermColor::Reset),
+            Print(format!(
+                " {:<9}{} ",
+                Palette::slot_name(slot),
+                color.to_hex()
+            ))
+        )?;
+        let values = [
+            format!("{:3}", color[0]),
+            format!("{:3}", color[1]),
+            format!("{:3}", color[2]),
+            format!("{:3.0}", h),
+            format!("{:3.0}", s * 100.0),
+            format!("{:3.0}", l * 100.0),
+        ];
+        for (channel, value) in Channel::ALL.iter().zip(values) {
+            let highlight = selected && *channel == self.channel;
+            if highlight {
+                queue!(stdout, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(stdout, Print(format!("{}{}", channel.label(), value)))?;
+            if highlight {
+                queue!(stdout, SetAttribute(Attribute::Reset))?;
+            }
+            queue!(stdout, Print(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hsl_step_always_changes_color() {
+        let color: Color = [0x10, 0x12, 0x11];
+        for channel in [Channel::Hue, Channel::Saturation, Channel::Lightness] {
+            for steps in [1, -1] {
+                assert_ne!(channel.adjust(color, steps), color, "{channel:?} {steps}");
+            }
+        }
+    }
+
+    #[test]
+    fn hsl_step_at_limit_keeps_color() {
+        let white: Color = [255, 255, 255];
+        assert_eq!(Channel::Lightness.adjust(white, 1), white);
+        assert_eq!(Channel::Hue.adjust(white, 1), white);
+    }
+}
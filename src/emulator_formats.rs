@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::helpers::Helpers;
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+
+use crate::palette::{Color, Colors, Palette};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot recognize palette format, use --from to specify it")]
+    UnknownFormat,
+    #[error("Missing value {0}")]
+    MissingValue(String),
+    #[error("Invalid color value '{1}' for {0}")]
+    InvalidValue(String, String),
+    #[error("Invalid SameBoy palette: {0}")]
+    InvalidSameBoy(String),
+    #[error(transparent)]
+    Pocket(#[from] crate::palette::Error),
+    #[error("MiSTer palette must be exactly 12 bytes, but is {0} bytes")]
+    InvalidMister(usize),
+    #[error("Error while accessing file {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// Palette formats of emulators, and how 4 layers + lcd_off of Pocket palette are folded into them
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Analogue Pocket .pal file (56 bytes)
+    Pocket,
+    /// mGBA gb.pal[0] - gb.pal[11] config values: background, obj0 and obj1.
+    /// Window uses background colors and lcd_off is dropped
+    Mgba,
+    /// BGB ini Color0 - Color3 values: BGB uses single palette for all layers,
+    /// so layers are collapsed and lcd_off is dropped
+    Bgb,
+    /// RetroArch gambatte text .pal: background, obj0 (Sprite 1) and obj1 (Sprite 2).
+    /// Window uses background colors and lcd_off is dropped
+    Gambatte,
+    /// SameBoy .sbp theme: SameBoy uses single palette for all layers,
+    /// so layers are collapsed, lcd_off is used as disabled LCD color
+    Sameboy,
+    /// MiSTer Gameboy core .gbp: MiSTer uses single palette for all layers,
+    /// so layers are collapsed and lcd_off is dropped
+    Mister,
+}
+
+/// How layers are collapsed into single one, for formats which have only one palette
+#[derive(Debug, Copy, Clone, Default, PartialEq, ValueEnum)]
+pub enum Collapse {
+    /// Use background colors only
+    #[default]
+    Bg,
+    /// Average of background, window, obj0 and obj1 colors for each shade
+    Average,
+}
+
+impl Collapse {
+    /// Palette with the same 4 shades on every layer, lcd_off is kept
+    pub fn apply(&self, palette: &Palette) -> Palette {
+        let shades: Colors = match self {
+            Collapse::Bg => std::array::from_fn(|shade| palette.slot(shade)),
+            Collapse::Average => std::array::from_fn(|shade| {
+                std::array::from_fn(|channel| {
+                    let sum: u32 = (0..4)
+                        .map(|layer| palette.slot(layer * 4 + shade)[channel] as u32)
+                        .sum();
+                    ((sum + 2) / 4) as u8
+                })
+            }),
+        };
+        Palette::from_shades(shades, palette.slot(16))
+    }
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Pocket => "pal",
+            Format::Mgba => "ini",
+            Format::Bgb => "ini",
+            Format::Gambatte => "pal",
+            Format::Sameboy => "sbp",
+            Format::Mister => "gbp",
+        }
+    }
+
+    /// What happens to layers which target format does not have
+    pub fn folding(&self) -> &'static str {
+        match self {
+            Format::Pocket => "all layers are kept",
+            Format::Mgba | Format::Gambatte => "window uses background colors, lcd_off is dropped",
+            Format::Bgb | Format::Mister => "layers are collapsed, lcd_off is dropped",
+            Format::Sameboy => "layers are collapsed, lcd_off is used as disabled LCD color",
+        }
+    }
+
+    const MISTER_SIZE: usize = 4 * 3;
+
+    /// Formats with single palette for all layers, where [`Collapse`] matters
+    pub fn is_single_palette(&self) -> bool {
+        matches!(self, Format::Bgb | Format::Sameboy | Format::Mister)
+    }
+
+    /// Guess format from file extension, contents decide between formats sharing extension.
+    ///
+    /// MiSTer palette has no signature, any 12 byte file looks like one, so its size is used
+    /// only for files with unknown extension which do not look like any other format
+    pub fn detect(file_name: &str, data: &[u8]) -> Option<Self> {
+        let extension = Path::new(file_name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("sbp") => Some(Format::Sameboy),
+            Some("gbp") => Some(Format::Mister),
+            Some(extension @ ("pal" | "ini")) => {
+                Self::detect_contents(data).filter(|format| format.extension() == extension)
+            }
+            _ => Self::detect_contents(data)
+                .or((data.len() == Self::MISTER_SIZE).then_some(Format::Mister)),
+        }
+    }
+
+    /// Guess format from signatures and keys in file contents
+    fn detect_contents(data: &[u8]) -> Option<Self> {
+        if data.starts_with(SameBoy::MAGIC) {
+            return Some(Format::Sameboy);
+        }
+        if data.len() == 56 && data.ends_with(&[0x81, b'A', b'P', b'G', b'B']) {
+            return Some(Format::Pocket);
+        }
+        let text = String::from_utf8_lossy(data);
+        if text.contains("gb.pal[") {
+            Some(Format::Mgba)
+        } else if text.contains("Background0") {
+            Some(Format::Gambatte)
+        } else if text.contains("Color0") {
+            Some(Format::Bgb)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Converter;
+
+impl Converter {
+    /// Read palette in any format, built-in palettes can be used too
+    pub fn read(file_name: &str, format: Option<Format>) -> Result<Palette, Error> {
+        if Library::is_builtin(file_name) {
+            return Ok(Palette::load(file_name)?);
+        }
+        let data = fs::read(file_name)?;
+        let format = format
+            .or_else(|| Format::detect(file_name, &data))
+            .ok_or(Error::UnknownFormat)?;
+        info!("Reading {} as {:?} palette", file_name, format);
+        Self::import(&data, format)
+    }
+
+    /// Write palette in given format, layers are collapsed first for single palette formats
+    pub fn write(
+        palette: &Palette,
+        file_name: &str,
+        format: Format,
+        collapse: Collapse,
+    ) -> Result<(), Error> {
+        let palette = if format.is_single_palette() {
+            info!(
+                "Writing {} as {:?} palette: {} ({:?})",
+                file_name,
+                format,
+                format.folding(),
+                collapse
+            );
+            collapse.apply(palette)
+        } else {
+            info!(
+                "Writing {} as {:?} palette: {}",
+                file_name,
+                format,
+                format.folding()
+            );
+            palette.clone()
+        };
+        fs::write(file_name, Self::export(&palette, format))?;
+        Ok(())
+    }
+
+    /// Convert all palettes in folder (recursively), keeping its structure.
+    ///
+    /// Returns number of converted palettes, palettes which cannot be read are skipped
+    pub fn convert_folder(
+        input_dir: &str,
+        output_dir: &str,
+        from: Format,
+        to: Format,
+        collapse: Collapse,
+    ) -> Result<usize, Error> {
+        let pattern = Path::new(input_dir).join(format!("**/*.{}", from.extension()));
+        let mut converted = 0;
+        for input_file in Helpers::glob_paths(&[pattern.to_string_lossy().to_string()]) {
+            let relative = Path::new(&input_file)
+                .strip_prefix(input_dir)
+                .unwrap_or(Path::new(&input_file));
+            let output_file = Path::new(output_dir)
+                .join(relative)
+                .with_extension(to.extension());
+            let palette = match Self::read(&input_file, Some(from)) {
+                Ok(palette) => palette,
+                Err(err) => {
+                    warn!("Skipping {input_file}: {err}");
+                    continue;
+                }
+            };
+            if let Some(parent) = output_file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Self::write(&palette, &output_file.to_string_lossy(), to, collapse)?;
+            if let Some(text) = PaletteMetadata::sidecar_text(&input_file) {
+                fs::write(PaletteMetadata::sidecar_file(&output_file), text)?;
+            }
+            converted += 1;
+        }
+        Ok(converted)
+    }
+
+    pub fn export(palette: &Palette, format: Format) -> Vec<u8> {
+        match format {
+            Format::Pocket => {
+                let mut data: Vec<u8> = palette.clone().into();
+                data.extend([0x81, b'A', b'P', b'G', b'B']);
+                data
+            }
+            Format::Mgba => {
+                let mut text = String::from(
+                    "; mGBA Game Boy palette, put these lines into config.ini settings section\n",
+                );
+                for slot in 0..12 {
+                    text += &format!("gb.pal[{}]={}\n", slot, Self::rgb_value(palette.slot(slot)));
+                }
+                text.into_bytes()
+            }
+            Format::Bgb => {
+                // BGB stores colors as Windows COLORREF, which is 0xBBGGRR
+                let mut text = String::from("; BGB palette, put these lines into bgb.ini\n");
+                for slot in 0..4 {
+                    let [r, g, b] = palette.slot(slot);
+                    text += &format!("Color{}={:02X}{:02X}{:02X}\n", slot, b, g, r);
+                }
+                text.into_bytes()
+            }
+            Format::Gambatte => {
+                let mut text = String::from("[General]\n");
+                for (layer, first) in Self::GAMBATTE_LAYERS {
+                    for shade in 0..4 {
+                        let value = Self::rgb_value(palette.slot(first + shade));
+                        text += &format!("{}{}={}\n", layer, shade, value);
+                    }
+                }
+                text.into_bytes()
+            }
+            Format::Sameboy => SameBoy::export(palette),
+            // Raw RGB colors, from lightest to darkest
+            Format::Mister => (0..4).flat_map(|slot| palette.slot(slot)).collect(),
+        }
+    }
+
+    pub fn import(data: &[u8], format: Format) -> Result<Palette, Error> {
+        match format {
+            Format::Pocket => Ok(data.to_vec().try_into()?),
+            Format::Mgba => {
+                let values = Self::ini_values(data);
+                let color = |slot: usize| Self::ini_color(&values, &format!("gb.pal[{slot}]"));
+                let layer = |first: usize| -> Result<Colors, Error> {
+                    Ok([
+                        color(first)?,
+                        color(first + 1)?,
+                        color(first + 2)?,
+                        color(first + 3)?,
+                    ])
+                };
+                let bg = layer(0)?;
+                Ok(Palette::new(bg, layer(4)?, layer(8)?, bg, bg[0]))
+            }
+            Format::Bgb => {
+                let values = Self::ini_values(data);
+                let mut shades = [[0; 3]; 4];
+                for (shade, color) in shades.iter_mut().enumerate() {
+                    let key = format!("Color{shade}");
+                    let value = values
+                        .get(&key)
+                        .ok_or_else(|| Error::MissingValue(key.clone()))?;
+                    let bgr = u32::from_str_radix(value, 16)
+                        .map_err(|_| Error::InvalidValue(key.clone(), value.clone()))?;
+                    *color = [bgr as u8, (bgr >> 8) as u8, (bgr >> 16) as u8];
+                }
+                Ok(Palette::from_shades(shades, shades[0]))
+            }
+            Format::Gambatte => {
+                let values = Self::ini_values(data);
+                let mut layers = [[[0; 3]; 4]; 3];
+                for ((layer, _), colors) in Self::GAMBATTE_LAYERS.iter().zip(layers.iter_mut()) {
+                    for (shade, color) in colors.iter_mut().enumerate() {
+                        *color = Self::ini_color(&values, &format!("{layer}{shade}"))?;
+                    }
+                }
+                let [bg, obj0, obj1] = layers;
+                Ok(Palette::new(bg, obj0, obj1, bg, bg[0]))
+            }
+            Format::Sameboy => SameBoy::import(data),
+            Format::Mister => {
+                if data.len() != Format::MISTER_SIZE {
+                    return Err(Error::InvalidMister(data.len()));
+                }
+                let shades: Colors = std::array::from_fn(|shade| {
+                    [data[shade * 3], data[shade * 3 + 1], data[shade * 3 + 2]]
+                });
+                Ok(Palette::from_shades(shades, shades[0]))
+            }
+        }
+    }
+
+    /// Gambatte keys are URL encoded, so 'Sprite%2010' is 'Sprite 10'
+    const GAMBATTE_LAYERS: [(&'static str, usize); 3] =
+        [("Background", 0), ("Sprite%201", 4), ("Sprite%202", 8)];
+
+    fn rgb_value(color: Color) -> u32 {
+        (color[0] as u32) << 16 | (color[1] as u32) << 8 | color[2] as u32
+    }
+
+    /// `key=value` lines, sections and comments are ignored
+    fn ini_values(data: &[u8]) -> HashMap<String, String> {
+        String::from_utf8_lossy(data)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect()
+    }
+
+    /// 0xRRGGBB color stored as decimal or 0x prefixed hex number
+    fn ini_color(values: &HashMap<String, String>, key: &str) -> Result<Color, Error> {
+        let value = values
+            .get(key)
+            .ok_or_else(|| Error::MissingValue(key.to_string()))?;
+        let rgb = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| Error::InvalidValue(key.to_string(), value.clone()))?;
+        Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+    }
+}
+
+/// SameBoy theme file (.sbp), little endian:
+/// * magic 'LPBS'
+/// * flags byte: bit 0 manual (colors are not interpolated), bit 1 disabled LCD color is used
+/// * 5 RGB colors: 4 shades from darkest to lightest, then color of disabled LCD
+/// * brightness bias, hue bias and hue bias strength (32 bits each), zero for us
+struct SameBoy;
+
+impl SameBoy {
+    const MAGIC: &'static [u8] = b"LPBS";
+    const SIZE: usize = 4 + 1 + 5 * 3 + 3 * 4;
+
+    fn export(palette: &Palette) -> Vec<u8> {
+        let mut data = Self::MAGIC.to_vec();
+        data.push(0b11);
+        // Pocket palettes go from lightest to darkest
+        for slot in (0..4).rev() {
+            data.extend(palette.slot(slot));
+        }
+        data.extend(palette.slot(16));
+        data.extend([0; 12]);
+        data
+    }
+
+    fn import(data: &[u8]) -> Result<Palette, Error> {
+        if data.len() < Self::SIZE || !data.starts_with(Self::MAGIC) {
+            return Err(Error::InvalidSameBoy(format!(
+                "expected 'LPBS' and at least {} bytes",
+                Self::SIZE
+            )));
+        }
+        let color = |index: usize| -> Color {
+            let start = 5 + index * 3;
+            [data[start], data[start + 1], data[start + 2]]
+        };
+        let shades = [color(3), color(2), color(1), color(0)];
+        let lcd_off = if data[4] & 0b10 != 0 {
+            color(4)
+        } else {
+            shades[0]
+        };
+        Ok(Palette::from_shades(shades, lcd_off))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Format; 6] = [
+        Format::Pocket,
+        Format::Mgba,
+        Format::Bgb,
+        Format::Gambatte,
+        Format::Sameboy,
+        Format::Mister,
+    ];
+
+    const BG: Colors = [
+        [0xE0, 0xF8, 0xD0],
+        [0x88, 0xC0, 0x70],
+        [0x34, 0x68, 0x56],
+        [0x08, 0x18, 0x20],
+    ];
+    const OBJ0: Colors = [
+        [0xFF, 0xFF, 0xFF],
+        [0xFF, 0x84, 0x84],
+        [0x94, 0x3A, 0x3A],
+        [0x00, 0x00, 0x00],
+    ];
+    const OBJ1: Colors = [
+        [0xFF, 0xFF, 0xFF],
+        [0x63, 0xA5, 0xFF],
+        [0x00, 0x00, 0xFF],
+        [0x00, 0x00, 0x00],
+    ];
+    const WINDOW: Colors = [
+        [0xFF, 0xEF, 0xCE],
+        [0xDE, 0xB5, 0x5A],
+        [0x9C, 0x6B, 0x21],
+        [0x31, 0x18, 0x00],
+    ];
+    const LCD_OFF: Color = [0xB0, 0xC8, 0xA0];
+
+    /// Palette which is kept after export to format, according to [`Format::folding`]
+    fn folded(palette: &Palette, format: Format) -> Palette {
+        let layer =
+            |first: usize| -> Colors { std::array::from_fn(|shade| palette.slot(first + shade)) };
+        let bg = layer(0);
+        match format {
+            Format::Pocket => palette.clone(),
+            Format::Mgba | Format::Gambatte => Palette::new(bg, layer(4), layer(8), bg, bg[0]),
+            Format::Bgb | Format::Mister => Palette::from_shades(bg, bg[0]),
+            Format::Sameboy => Palette::from_shades(bg, palette.slot(16)),
+        }
+    }
+
+    fn assert_same(palette: &Palette, expected: &Palette, format: Format) {
+        let slots = |palette: &Palette| (0..17).map(|slot| palette.slot(slot)).collect::<Vec<_>>();
+        assert_eq!(slots(palette), slots(expected), "{format:?}");
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let palette = Palette::default();
+        for format in ALL {
+            let data = Converter::export(&palette, format);
+            assert_eq!(
+                Format::detect(&format!("test.{}", format.extension()), &data),
+                Some(format)
+            );
+            let imported = Converter::import(&data, format).unwrap();
+            assert_same(&imported, &folded(&palette, format), format);
+        }
+    }
+
+    #[test]
+    fn reads_emulator_files() {
+        let fixtures: [(&str, &[u8], Palette); 6] = [
+            (
+                "pocket.pal",
+                include_bytes!("../test_assets/emulator_formats/pocket.pal"),
+                Palette::new(BG, OBJ0, OBJ1, WINDOW, LCD_OFF),
+            ),
+            (
+                "mgba_config.ini",
+                include_bytes!("../test_assets/emulator_formats/mgba_config.ini"),
+                Palette::new(BG, OBJ0, OBJ1, BG, BG[0]),
+            ),
+            (
+                "bgb.ini",
+                include_bytes!("../test_assets/emulator_formats/bgb.ini"),
+                Palette::from_shades(BG, BG[0]),
+            ),
+            (
+                "gambatte.pal",
+                include_bytes!("../test_assets/emulator_formats/gambatte.pal"),
+                Palette::new(BG, OBJ0, OBJ1, BG, BG[0]),
+            ),
+            (
+                "sameboy.sbp",
+                include_bytes!("../test_assets/emulator_formats/sameboy.sbp"),
+                Palette::from_shades(BG, LCD_OFF),
+            ),
+            (
+                "mister.gbp",
+                include_bytes!("../test_assets/emulator_formats/mister.gbp"),
+                Palette::from_shades(BG, BG[0]),
+            ),
+        ];
+        for (file_name, data, expected) in fixtures {
+            let format = Format::detect(file_name, data).unwrap();
+            let palette = Converter::import(data, format).unwrap();
+            assert_same(&palette, &expected, format);
+            // Exporting back keeps everything format can hold
+            let exported = Converter::export(&palette, format);
+            let palette = Converter::import(&exported, format).unwrap();
+            assert_same(&palette, &expected, format);
+        }
+    }
+
+    #[test]
+    fn collapses_single_palette_formats() {
+        let palette = Palette::new(BG, OBJ0, OBJ1, WINDOW, LCD_OFF);
+        let average = Collapse::Average.apply(&palette);
+        assert_eq!(average.slot(0), [0xF7, 0xF9, 0xE7]);
+        assert_eq!(average.slot(4), average.slot(0));
+        assert_eq!(average.slot(16), LCD_OFF);
+        let bg = Collapse::Bg.apply(&palette);
+        assert_same(&bg, &Palette::from_shades(BG, LCD_OFF), Format::Bgb);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(matches!(
+            Converter::import(b"gb.pal[0]=0x12345Z", Format::Mgba),
+            Err(Error::InvalidValue(..))
+        ));
+        assert!(matches!(
+            Converter::import(b"Color0=FFFFFF", Format::Bgb),
+            Err(Error::MissingValue(key)) if key == "Color1"
+        ));
+        assert!(matches!(
+            Converter::import(b"LPBS", Format::Sameboy),
+            Err(Error::InvalidSameBoy(_))
+        ));
+        assert!(matches!(
+            Converter::import(&[0; 13], Format::Mister),
+            Err(Error::InvalidMister(13))
+        ));
+        assert_eq!(Format::detect("unknown.pal", b"Color0=FFFFFF"), None);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use log::{debug, info};
+use serde::Serialize;
+use tera::Context;
+
+use crate::helpers::Helpers;
+use crate::image_handler::{ColorizeOptions, Error, ImageHandler, TEMPLATES};
+use crate::library::Library;
+use crate::naming::NameTemplate;
+
+/// Palettes from one directory, as shown on top-level index
+#[derive(Serialize)]
+struct GalleryGroup<'a> {
+    name: &'a str,
+    palettes: &'a [HashMap<&'a str, String>],
+}
+
+/// Output directory which mirrors palette directories, e.g. `out/Trashuncle/GBP/foo/`
+/// for `packs/Trashuncle/GBP/foo.pal` found by `packs/**/*.pal`, so large palette packs
+/// stay browsable.
+///
+/// Each palette folder gets `index.html` with its images, and there is top-level `index.html`
+/// linking all of them
+pub struct Gallery {
+    root: PathBuf,
+}
+
+impl Gallery {
+    /// Directory of palette is in `{out_dir}` of its job, see [`Self::jobs`]
+    const NAME_TEMPLATE: &'static str = "{out_dir}/{palette_stem}/{image_stem}.png";
+    const INDEX_FILE: &'static str = "index.html";
+
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+        }
+    }
+
+    pub fn name_template() -> NameTemplate {
+        Self::NAME_TEMPLATE
+            .parse()
+            .expect("Gallery name template is valid")
+    }
+
+    /// Value for --output, only its directory is used by name template
+    pub fn output_image_file(&self) -> String {
+        self.root.join("output.png").to_string_lossy().to_string()
+    }
+
+    /// Palettes matched by patterns, each with output file in folder mirroring its directory
+    /// relative to base of pattern which matched it (path up to first wildcard), e.g.
+    /// `out/Trashuncle/GBP/output.png` for `packs/Trashuncle/GBP/foo.pal` and `packs/**/*.pal`.
+    ///
+    /// Built-in palettes go to `out/builtin/`
+    pub fn jobs(&self, pal_file_names: &[String]) -> Vec<(String, String)> {
+        let mut seen = HashSet::new();
+        let mut jobs = Vec::new();
+        for pattern in pal_file_names {
+            let base = Helpers::glob_base_dir(pattern);
+            for pal_file in Helpers::glob_paths(std::slice::from_ref(pattern)) {
+                if !seen.insert(pal_file.clone()) {
+                    continue;
+                }
+                let dir = match Library::is_builtin(&pal_file) {
+                    true => PathBuf::from("builtin"),
+                    false => {
+                        let parent = Path::new(&pal_file).parent().unwrap_or(Path::new(""));
+                        parent.strip_prefix(&base).unwrap_or(parent).to_path_buf()
+                    }
+                };
+                let folder = match NameTemplate::sanitize_dir(&dir).as_str() {
+                    "." => self.root.clone(),
+                    dir => self.root.join(dir),
+                };
+                let output_image_file = folder.join("output.png").to_string_lossy().to_string();
+                jobs.push((pal_file, output_image_file));
+            }
+        }
+        jobs.sort();
+        jobs
+    }
+
+    /// Path relative to gallery root, with '/' separators so it can be used in links
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .join("/")
+    }
+
+    /// Colorize screenshots with each palette into its own folder, then write HTML indexes.
+    ///
+    /// `options` must use [`Self::name_template`]
+    pub fn colorize(
+        &self,
+        pal_file_names: &[String],
+        input_images: &[String],
+        options: &ColorizeOptions,
+    ) -> Result<(), Error> {
+        let jobs = self.jobs(pal_file_names);
+        let written = ImageHandler::color_images_with_palettes(&jobs, input_images, options, None)?;
+        // Every palette has folder of its own, so outputs can be grouped by it
+        let mut images: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        for output in &written {
+            let path = Path::new(output);
+            if let (Some(folder), Some(file_name)) = (path.parent(), path.file_name()) {
+                images
+                    .entry(folder.to_path_buf())
+                    .or_default()
+                    .push(file_name.to_string_lossy().to_string());
+            }
+        }
+        // Palette folders by directory of palettes, for top-level index
+        let mut groups: BTreeMap<String, Vec<HashMap<&str, String>>> = BTreeMap::new();
+        for (pal_file, output_image_file) in &jobs {
+            let merged = ImageHandler::output_file(options, output_image_file, pal_file, None, 1);
+            let folder = Path::new(&merged)
+                .parent()
+                .expect("Gallery outputs are always in folder")
+                .to_path_buf();
+            let folder_images = images.remove(&folder).unwrap_or_default();
+            let mut info = ImageHandler::palette_info(pal_file);
+            self.write_palette_index(&folder, &info, &folder_images);
+            let link = self.relative(&folder.join(Self::INDEX_FILE));
+            let thumbnail = folder_images
+                .first()
+                .map(|image| self.relative(&folder.join(image)))
+                .unwrap_or_default();
+            let group = folder
+                .parent()
+                .map(|parent| self.relative(parent))
+                .unwrap_or_default();
+            info.insert("link", link);
+            info.insert("image", thumbnail);
+            info.insert("images", folder_images.len().to_string());
+            groups.entry(group).or_default().push(info);
+        }
+        self.write_index(&groups);
+        Ok(())
+    }
+
+    fn write_palette_index(&self, folder: &Path, info: &HashMap<&str, String>, images: &[String]) {
+        // Link back to top-level index, relative to this folder
+        let depth = folder
+            .strip_prefix(&self.root)
+            .map(|relative| relative.components().count())
+            .unwrap_or(0);
+        let mut context = Context::new();
+        context.insert("version", env!("GIT_HASH_SHORT"));
+        context.insert("p", info);
+        context.insert("images", images);
+        context.insert(
+            "root",
+            &format!("{}{}", "../".repeat(depth), Self::INDEX_FILE),
+        );
+        let rendered = TEMPLATES
+            .render("gallery_palette.html", &context)
+            .unwrap_or_else(|err| panic!("Cannot render palette index: {err}"));
+        let html_file = folder.join(Self::INDEX_FILE);
+        debug!("Writing {:?}", html_file);
+        fs::create_dir_all(folder)
+            .and_then(|_| fs::write(&html_file, rendered))
+            .unwrap_or_else(|err| panic!("Cannot create HTML file {:?}: {}", html_file, err));
+    }
+
+    fn write_index(&self, groups: &BTreeMap<String, Vec<HashMap<&str, String>>>) {
+        let groups: Vec<GalleryGroup> = groups
+            .iter()
+            .map(|(name, palettes)| GalleryGroup { name, palettes })
+            .collect();
+        let mut context = Context::new();
+        context.insert("version", env!("GIT_HASH_SHORT"));
+        context.insert("groups", &groups);
+        let rendered = TEMPLATES
+            .render("gallery.html", &context)
+            .unwrap_or_else(|err| panic!("Cannot render gallery index: {err}"));
+        let html_file = self.root.join(Self::INDEX_FILE);
+        fs::create_dir_all(&self.root)
+            .and_then(|_| fs::write(&html_file, rendered))
+            .unwrap_or_else(|err| panic!("Cannot create HTML file {:?}: {}", html_file, err));
+        info!("Created gallery index {:?}", html_file);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use image::DynamicImage;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use thiserror::Error;
+
+use crate::palette::{hex_colors, Color, ColorExt, Colors, Palette};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Image has {0} colors, but GBC compatibility palette has at most 12; is it screenshot of native Game Boy Color game? Those cannot be repalettized")]
+    NativeColor(usize),
+    #[error("Colors {0} are not part of any known GBC compatibility palette; is it screenshot of native Game Boy Color game? Those cannot be repalettized")]
+    UnknownColors(String),
+    #[error("Colors {colors} are not part of GBC compatibility palette '{palette}'")]
+    NotInPalette { palette: String, colors: String },
+    #[error("Unknown GBC compatibility palette '{0}', use one of: {1}")]
+    UnknownPalette(String, String),
+}
+
+/// Palette used by Game Boy Color for original Game Boy games (so called compatibility mode).
+///
+/// It only has 12 colors: background (window uses the same), and two object palettes.
+/// Colors are 24-bit versions of GBC 15-bit colors
+#[derive(Debug, Clone, PartialEq)]
+pub struct GbcCompatPalette {
+    pub name: String,
+    pub bg: Colors,
+    pub obj0: Colors,
+    pub obj1: Colors,
+}
+
+impl Display for GbcCompatPalette {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl GbcCompatPalette {
+    /// Screenshots may have slightly different colors than palette, depending on how
+    /// 15-bit colors were converted, so same tolerance as for template is used
+    const TOLERANCE: u8 = 8;
+
+    /// Palettes selectable by holding buttons during GBC boot animation.
+    ///
+    /// Colors and layer assignments as listed in Pan Docs, "Power Up Sequence",
+    /// table of compatibility palettes selectable by key combinations
+    pub fn boot_rom() -> Vec<Self> {
+        let brown = hex_colors!(0xFFFFFF, 0xFFAD63, 0x843100, 0x000000);
+        let red = hex_colors!(0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000);
+        let dark_brown = hex_colors!(0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108);
+        let blue = hex_colors!(0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000);
+        let dark_blue = hex_colors!(0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000);
+        let grey = hex_colors!(0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000);
+        let pastel = hex_colors!(0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000);
+        let orange = hex_colors!(0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000);
+        let yellow = hex_colors!(0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000);
+        let green = hex_colors!(0xFFFFFF, 0x7BFF31, 0x008400, 0x000000);
+        let lime = hex_colors!(0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000);
+        let default = hex_colors!(0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000);
+        let inverted = hex_colors!(0x000000, 0x008484, 0xFFDE00, 0xFFFFFF);
+        let palette = |name: &str, bg: Colors, obj0: Colors, obj1: Colors| Self {
+            name: name.to_string(),
+            bg,
+            obj0,
+            obj1,
+        };
+        vec![
+            palette("up", brown, brown, brown),
+            palette("up_a", red, green, blue),
+            palette("up_b", dark_brown, brown, brown),
+            palette("left", blue, red, green),
+            palette("left_a", dark_blue, red, brown),
+            palette("left_b", grey, grey, grey),
+            palette("down", pastel, pastel, pastel),
+            palette("down_a", orange, orange, orange),
+            palette("down_b", yellow, blue, green),
+            palette("right", lime, lime, lime),
+            palette("right_a", default, red, red),
+            palette("right_b", inverted, inverted, inverted),
+        ]
+    }
+
+    /// Boot ROM palette by name, or palette made of bg, obj0 and obj1 layers of .pal file
+    pub fn load(name: &str) -> Result<Self, Error> {
+        if let Some(palette) = Self::boot_rom()
+            .into_iter()
+            .find(|palette| palette.name == name)
+        {
+            return Ok(palette);
+        }
+        match Palette::load(name) {
+            Ok(palette) => Ok(Self::from_palette(name, &palette)),
+            Err(_) => Err(Error::UnknownPalette(
+                name.to_string(),
+                Self::boot_rom().iter().join(", "),
+            )),
+        }
+    }
+
+    pub fn from_palette(name: &str, palette: &Palette) -> Self {
+        let layer = |first: usize| -> Colors { std::array::from_fn(|i| palette.slot(first + i)) };
+        Self {
+            name: name.to_string(),
+            bg: layer(0),
+            obj0: layer(4),
+            obj1: layer(8),
+        }
+    }
+
+    /// Full palette, window uses background colors same as on GBC
+    pub fn to_palette(&self) -> Palette {
+        Palette::new(self.bg, self.obj0, self.obj1, self.bg, self.bg[0])
+    }
+
+    /// Palette slot for color, the first layer containing it is used when layers share colors
+    pub fn slot_of(&self, color: Color) -> Option<usize> {
+        self.bg
+            .iter()
+            .chain(&self.obj0)
+            .chain(&self.obj1)
+            .position(|c| Self::matches(*c, color))
+    }
+
+    fn matches(a: Color, b: Color) -> bool {
+        a.iter()
+            .zip(b)
+            .all(|(a, b)| a.abs_diff(b) <= Self::TOLERANCE)
+    }
+
+    fn colors_to_string(colors: &[Color]) -> String {
+        colors
+            .iter()
+            .map(|color| color.to_hex())
+            .sorted()
+            .join(", ")
+    }
+
+    /// Layers which cannot be told apart on screenshot, because they use the same colors
+    fn shared_layers(&self) -> Vec<(&'static str, &'static str)> {
+        let layers = [("bg", self.bg), ("obj0", self.obj0), ("obj1", self.obj1)];
+        layers
+            .iter()
+            .tuple_combinations()
+            .filter(|((_, a), (_, b))| a == b)
+            .map(|((a, _), (b, _))| (*a, *b))
+            .collect()
+    }
+
+    /// Find palette which contains all colors found on screenshot.
+    ///
+    /// If more palettes match, the one with the least colors not used on screenshot is chosen
+    pub fn detect(colors: &HashSet<Color>, candidates: &[Self]) -> Result<Self, Error> {
+        if colors.len() > 12 {
+            return Err(Error::NativeColor(colors.len()));
+        }
+        let matching: Vec<(usize, &Self)> = candidates
+            .iter()
+            .filter(|palette| colors.iter().all(|color| palette.slot_of(*color).is_some()))
+            .map(|palette| {
+                let palette_colors: HashSet<Color> = palette
+                    .bg
+                    .iter()
+                    .chain(&palette.obj0)
+                    .chain(&palette.obj1)
+                    .copied()
+                    .collect();
+                let unused = palette_colors
+                    .iter()
+                    .filter(|c| !colors.iter().any(|color| Self::matches(**c, *color)))
+                    .count();
+                (unused, palette)
+            })
+            .sorted_by_key(|(unused, _)| *unused)
+            .collect();
+        match matching.as_slice() {
+            [] => {
+                let unknown: Vec<Color> = colors
+                    .iter()
+                    .filter(|color| candidates.iter().all(|p| p.slot_of(**color).is_none()))
+                    .copied()
+                    .collect();
+                let unknown = if unknown.is_empty() {
+                    colors.iter().copied().collect()
+                } else {
+                    unknown
+                };
+                Err(Error::UnknownColors(Self::colors_to_string(&unknown)))
+            }
+            [(unused, palette), rest @ ..] => {
+                let tied: Vec<&str> = rest
+                    .iter()
+                    .filter(|(other, _)| other == unused)
+                    .map(|(_, palette)| palette.name.as_str())
+                    .collect();
+                if !tied.is_empty() {
+                    warn!(
+                        "Screenshot matches multiple GBC compatibility palettes: {}, {}; using {}",
+                        palette.name,
+                        tied.join(", "),
+                        palette.name
+                    );
+                }
+                Ok((*palette).clone())
+            }
+        }
+    }
+
+    /// Convert GBC compatibility mode screenshot to [`Palette`] slot indexes.
+    ///
+    /// Background and window share palette on GBC, so both end up in background slots.
+    /// If `palette` is not given, it is detected from boot ROM palettes
+    pub fn palettize(image: &DynamicImage, palette: Option<&Self>) -> Result<Vec<u8>, Error> {
+        let rgb = image.to_rgb8();
+        let colors: HashSet<Color> = rgb.pixels().map(|pixel| pixel.0).collect();
+        let palette = match palette {
+            Some(palette) => {
+                if colors.len() > 12 {
+                    return Err(Error::NativeColor(colors.len()));
+                }
+                let missing: Vec<Color> = colors
+                    .iter()
+                    .filter(|color| palette.slot_of(**color).is_none())
+                    .copied()
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(Error::NotInPalette {
+                        palette: palette.name.clone(),
+                        colors: Self::colors_to_string(&missing),
+                    });
+                }
+                palette.clone()
+            }
+            None => {
+                let palette = Self::detect(&colors, &Self::boot_rom())?;
+                info!("Detected GBC compatibility palette: {}", palette.name);
+                palette
+            }
+        };
+        for (a, b) in palette.shared_layers() {
+            debug!(
+                "Layers {a} and {b} of GBC compatibility palette '{}' use the same colors, they cannot be told apart",
+                palette.name
+            );
+        }
+        // Only few colors, so lookup is not worth it here
+        let slots: Vec<(Color, u8)> = colors
+            .iter()
+            .map(|color| (*color, palette.slot_of(*color).unwrap() as u8))
+            .collect();
+        Ok(rgb
+            .pixels()
+            .map(|pixel| {
+                slots
+                    .iter()
+                    .find(|(color, _)| *color == pixel.0)
+                    .map(|(_, slot)| *slot)
+                    .unwrap()
+            })
+            .collect())
+    }
+}
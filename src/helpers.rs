@@ -1,13 +1,28 @@
 use glob::glob;
 use itertools::Itertools;
+use log::warn;
+use std::path::{Path, PathBuf};
+
+use crate::library::Library;
 
 pub struct Helpers;
 
 impl Helpers {
+    /// Log file, written into current directory
+    pub const LOG_FILE: &'static str = concat!(env!("CARGO_PKG_NAME"), ".log");
+
+    /// Expand glob patterns, including `builtin:` palette names
     pub fn glob_paths(paths: &[String]) -> Vec<String> {
         let mut paths_globbed: Vec<String> = Vec::new();
         paths.iter().for_each(|input_image| {
-            if input_image.contains(&"*".to_string()) {
+            if Library::is_builtin(input_image) {
+                // Built-in palettes are not files, but can be globbed by name too
+                let builtins = Library::glob(input_image);
+                if builtins.is_empty() {
+                    warn!("No built-in palette matches {}", input_image);
+                }
+                paths_globbed.extend(builtins);
+            } else if input_image.contains(&"*".to_string()) {
                 let paths = glob(input_image)
                     .unwrap_or_else(|_| panic!("Incorrect glob pattern: {}", input_image));
                 paths.for_each(|path| {
@@ -24,4 +39,55 @@ impl Helpers {
         paths_globbed.sort();
         paths_globbed
     }
+
+    /// Directory which contains all files matched by glob pattern,
+    /// i.e. path up to first component containing any wildcard
+    pub fn glob_base_dir(pattern: &str) -> PathBuf {
+        let path = Path::new(pattern);
+        if !pattern.contains(['*', '?', '[']) {
+            return match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+        }
+        let base: PathBuf = path
+            .components()
+            .take_while(|component| {
+                !component
+                    .as_os_str()
+                    .to_string_lossy()
+                    .contains(['*', '?', '['])
+            })
+            .collect();
+        if base.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            base
+        }
+    }
+
+    /// Decode percent-encoded URL query component, '+' is treated as space
+    pub fn url_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => decoded.push(b' '),
+                b'%' if i + 2 < bytes.len() => {
+                    let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                    match u8::from_str_radix(hex, 16) {
+                        Ok(byte) => {
+                            decoded.push(byte);
+                            i += 2;
+                        }
+                        Err(_) => decoded.push(b'%'),
+                    }
+                }
+                byte => decoded.push(byte),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).to_string()
+    }
 }
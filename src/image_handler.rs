@@ -1,36 +1,71 @@
-use crate::palette::{AsAnsi, AsAnsiType, AsAnsiVec, Color, Palette};
+use crate::palette::{AsAnsiType, AsAnsiVec, ColorExt, Palette};
 
 use image::io::Reader;
-use image::{DynamicImage, GenericImageView, Pixel};
+use image::{DynamicImage, ImageError};
 use log::{debug, info, warn};
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{Cursor, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::process::exit;
+use std::sync::{Arc, Mutex};
 
 use clap::ValueEnum;
+use itertools::Itertools;
 
+use crate::cache::BuildCache;
+use crate::gbc::{self, GbcCompatPalette};
 use crate::helpers::Helpers;
-use crate::png_helper::{PngHelper, PngPalette};
+use crate::metadata::PaletteMetadata;
+use crate::naming::{self, NameContext, NameTemplate};
+use crate::png_helper::{ColorLookup, PngHelper, PngPalette};
+use crate::screenshot::{ScreenshotClassifier, ScreenshotKind};
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
+use thiserror::Error;
 
 lazy_static! {
+    /// Templates are embedded in binary, so it works from any directory
     pub static ref TEMPLATES: Tera = {
-        let mut tera = match Tera::new("templates/**/*") {
-            Ok(t) => t,
-            Err(e) => {
-                println!("Template parsing error(s): {}", e);
-                exit(1);
-            }
-        };
-        tera.autoescape_on(vec![]);
+        let mut tera = Tera::default();
+        if let Err(e) = tera.add_raw_templates(vec![
+            ("style.css", include_str!("../templates/style.css")),
+            ("gallery.html", include_str!("../templates/gallery.html")),
+            ("gallery_palette.html", include_str!("../templates/gallery_palette.html")),
+            ("index.html", include_str!("../templates/index.html")),
+            ("serve.html", include_str!("../templates/serve.html")),
+            ("stats.html", include_str!("../templates/stats.html")),
+        ]) {
+            println!("Template parsing error(s): {}", e);
+            exit(1);
+        }
+        // Palette metadata comes from whoever made the palette, so it must not be able to inject HTML
+        tera.autoescape_on(vec![".html"]);
         tera
     };
+    /// Color lookup tables by template palette bytes
+    static ref TEMPLATE_LOOKUPS: Mutex<HashMap<Vec<u8>, Arc<ColorLookup>>> =
+        Mutex::new(HashMap::new());
 }
 
-#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot use {0}: {1}")]
+    GbcCompat(String, gbc::Error),
+    #[error("Cannot open image file {0}: {1}")]
+    Image(String, ImageError),
+    #[error("Cannot read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("{0} is {1}")]
+    NotTemplate(String, ScreenshotKind),
+    #[error("{0}, nothing was written")]
+    Collision(#[from] naming::Error),
+}
+
+#[derive(Debug, Copy, Clone, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MergeLayout {
     #[default]
     #[clap(alias = "h")]
@@ -39,6 +74,50 @@ pub enum MergeLayout {
     Vertical,
 }
 
+impl MergeLayout {
+    /// Row and column of image number `index` in grid of `rows` x `columns`.
+    ///
+    /// Horizontal layout fills rows first, vertical one fills columns first
+    pub fn position(&self, index: usize, rows: usize, columns: usize) -> (usize, usize) {
+        match self {
+            MergeLayout::Horizontal => (index / columns, index % columns),
+            MergeLayout::Vertical => (index % rows, index / rows),
+        }
+    }
+}
+
+/// How colors of screenshots are mapped to palette slots
+#[derive(Debug, Clone, Default)]
+pub enum ColorMode {
+    /// Screenshots were taken using template palette, see create-template-pal
+    #[default]
+    Template,
+    /// Screenshots of GB games colorized by GBC compatibility palette,
+    /// detected from boot ROM palettes if not given
+    GbcCompat(Option<GbcCompatPalette>),
+}
+
+/// Options shared by all colorization runs
+#[derive(Debug, Clone)]
+pub struct ColorizeOptions {
+    pub output_scale: Option<u8>,
+    pub merge: bool,
+    pub max_columns: u8,
+    pub merge_layout: MergeLayout,
+    /// Merge: palette slot used to fill space around images, if not set unused palette index is used
+    pub pad_slot: Option<usize>,
+    pub color_mode: ColorMode,
+    /// Palette screenshots were taken with, for [`ColorMode::Template`]
+    pub template: Palette,
+    /// Quantize palettes to BGR555 before use
+    pub quantize_555: bool,
+    pub generate_html: bool,
+    /// Names of output files, instead of ones derived from --output
+    pub name_template: Option<NameTemplate>,
+    /// Skip outputs which were already created from the same inputs
+    pub cache: Option<Arc<BuildCache>>,
+}
+
 pub struct ImageHandler;
 
 impl ImageHandler {
@@ -47,62 +126,98 @@ impl ImageHandler {
     const ALMOST_ALL_COLORS: usize = 16;
 
     /// For some reason colors found on screenshots so give us some tolerance around that
-    const TEMPLATE_TOLERANCE_UPPER: u8 = 8;
-    const TEMPLATE_TOLERANCE_LOWER: u8 = 8;
-
-    fn find_unique_colors(image: &DynamicImage) -> HashSet<Color> {
-        let mut colors = HashSet::new();
-        for pixel in image.pixels() {
-            let (_, _, color) = pixel;
-            // no need to use alpha channel here
-            let color = color.to_rgb().0;
-            colors.insert(color);
-        }
-        debug!("Found {} unique colors in image", colors.len());
-        colors
+    pub const TEMPLATE_TOLERANCE: u8 = 8;
+
+    /// Maximum size of scaled palette index buffers of screenshots processed at once
+    const MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+    /// Pairs of template slots which are so close, that screenshot colors could be matched to wrong slot
+    pub fn template_conflicts(template: &Palette) -> Vec<(usize, usize)> {
+        template.template_conflicts(Self::TEMPLATE_TOLERANCE * 2 + 1)
     }
 
-    fn palettize_image(template: Palette, image: &DynamicImage) -> Vec<u8> {
-        let colors = Self::find_unique_colors(image);
-        let percentage_of_colors = colors.len() as f32 / Self::ALMOST_ALL_COLORS as f32 * 100.0;
-        if percentage_of_colors >= 100.0 {
-            info!("All colors from palette (except lcd_off) have representation in source image");
-        } else {
-            warn!("Only ~{:.2}% of colors have representation in source image ({} of {}; not counting lcd_off)", percentage_of_colors, colors.len(), Self::ALMOST_ALL_COLORS)
-        }
-        let template: PngPalette = template.into();
+    /// Lookup table for template palette, built only once as it is quite big
+    fn template_lookup(template: &Palette) -> Arc<ColorLookup> {
+        let key: Vec<u8> = template.clone().into();
+        let mut lookups = TEMPLATE_LOOKUPS.lock().unwrap();
+        lookups
+            .entry(key)
+            .or_insert_with(|| {
+                debug!("Building color lookup table for template palette");
+                let template: PngPalette = template.clone().into();
+                Arc::new(ColorLookup::new(&template, Self::TEMPLATE_TOLERANCE))
+            })
+            .clone()
+    }
+
+    /// Open and decode image file, panics if that is not possible
+    pub fn load_image(image_path: &str) -> DynamicImage {
+        Self::try_load_image(image_path).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_load_image(image_path: &str) -> Result<DynamicImage, Error> {
+        debug!("Opening image file {}", image_path);
+        let image = Reader::open(image_path)
+            .map_err(ImageError::IoError)
+            .and_then(|reader| reader.decode())
+            .map_err(|err| Error::Image(image_path.to_string(), err))?;
+        info!("Opened image file {}", image_path);
+        Ok(image)
+    }
+
+    /// Convert screenshot created with `template` palette to vector of palette indexes.
+    ///
+    /// Returned vector has `width * height` length, so it can be saved directly as paletted .png
+    /// with any other palette applied
+    pub fn palettize_image(template: Palette, image: &DynamicImage) -> Vec<u8> {
+        let lookup = Self::template_lookup(&template);
+        let converted;
+        let rgb = match image.as_rgb8() {
+            Some(rgb) => rgb,
+            None => {
+                // no need to use alpha channel here
+                converted = image.to_rgb8();
+                &converted
+            }
+        };
         let (width, height) = (image.width() as usize, image.height() as usize);
         let mut image_buffer = vec![255_u8; width * height];
 
-        let mut position = 0_usize;
-        for (_, _, color) in image.pixels() {
-            let color = color.to_rgb().0;
-            let color_index = template.index_of_with_tolerance(color, 8);
-            // We just store color index in Vector, because this is how paletted images really work
-            // Because we will be supplying different palette when saving - this will colorize our image
-            // Much faster than previously used here PNG RGBA and manually putting whole RGBA pixels
-            // However we will be needed to implement scaling and merging ourselves - can we do it?
-            if let Some(color_index) = color_index {
-                image_buffer[position] = color_index as u8; // palette index will never exceed u8 size
-            }
-            position += 1;
+        // We just store color index in Vector, because this is how paletted images really work
+        // Because we will be supplying different palette when saving - this will colorize our image
+        // Each row is done in parallel, and we also note which palette indexes were found in it
+        let found = image_buffer
+            .par_chunks_mut(width.max(1))
+            .zip(rgb.as_raw().par_chunks(width.max(1) * 3))
+            .map(|(row, pixels)| {
+                let mut found = [false; 256];
+                for (index, pixel) in row.iter_mut().zip(pixels.chunks_exact(3)) {
+                    *index = lookup.index_of([pixel[0], pixel[1], pixel[2]]);
+                    found[*index as usize] = true;
+                }
+                found
+            })
+            .reduce(
+                || [false; 256],
+                |mut a, b| {
+                    a.iter_mut().zip(b).for_each(|(a, b)| *a |= b);
+                    a
+                },
+            );
+        let colors = found[..Self::ALMOST_ALL_COLORS]
+            .iter()
+            .filter(|found| **found)
+            .count();
+        let percentage_of_colors = colors as f32 / Self::ALMOST_ALL_COLORS as f32 * 100.0;
+        if percentage_of_colors >= 100.0 {
+            info!("All colors from palette (except lcd_off) have representation in source image");
+        } else {
+            warn!("Only ~{:.2}% of colors have representation in source image ({} of {}; not counting lcd_off)", percentage_of_colors, colors, Self::ALMOST_ALL_COLORS)
         }
         image_buffer
     }
 
-    fn save_image(image: &DynamicImage, image_path: &str) {
-        let mut bytes: Vec<u8> = Vec::new();
-        image
-            .write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)
-            .expect("Cannot create output file bytes");
-        let mut file = File::create(image_path)
-            .unwrap_or_else(|_| panic!("Cannot create image file {}", &image_path));
-        file.write_all(&bytes)
-            .unwrap_or_else(|_| panic!("Cannot write to image file {}", &image_path));
-        info!("Saved image file {}", image_path);
-    }
-
-    fn scale_paletted_image(
+    pub fn scale_paletted_image(
         image_array: &[u8],
         width: usize,
         height: usize,
@@ -137,20 +252,415 @@ impl ImageHandler {
         scaled_array
     }
 
+    /// Open screenshot and palettize it, using cache if available as result does not depend
+    /// on palette used for colorization.
+    ///
+    /// With `classifier` screenshot is checked first, see [`Self::check_screenshot`],
+    /// so it is decoded only once. Cached screenshots were already checked.
+    ///
+    /// Returns width, height and palette indexes
+    pub fn palettize_file(
+        template: &Palette,
+        input_image: &str,
+        color_mode: &ColorMode,
+        cache: Option<&BuildCache>,
+        classifier: Option<&ScreenshotClassifier>,
+    ) -> Result<(usize, usize, Vec<u8>), Error> {
+        let key = cache
+            .map(|cache| {
+                let template_bytes: Vec<u8> = template.clone().into();
+                let file_hash = cache
+                    .file_hash(input_image)
+                    .map_err(|err| Error::Read(input_image.to_string(), err))?;
+                Ok::<_, Error>(BuildCache::hash(&[
+                    &template_bytes,
+                    file_hash.as_bytes(),
+                    &[Self::TEMPLATE_TOLERANCE],
+                    format!("{:?}", color_mode).as_bytes(),
+                ]))
+            })
+            .transpose()?;
+        if let (Some(cache), Some(key)) = (cache, &key) {
+            if let Some(palettized) = cache.palettized(key) {
+                debug!("Using cached palettized image for {}", input_image);
+                return Ok(palettized);
+            }
+        }
+        let image = Self::try_load_image(input_image)?;
+        if let Some(classifier) = classifier {
+            Self::check_screenshot(classifier, input_image, &image)?;
+        }
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let palettized = match color_mode {
+            ColorMode::Template => Self::palettize_image(template.clone(), &image),
+            ColorMode::GbcCompat(palette) => GbcCompatPalette::palettize(&image, palette.as_ref())
+                .map_err(|err| Error::GbcCompat(input_image.to_string(), err))?,
+        };
+        if let (Some(cache), Some(key)) = (cache, &key) {
+            cache.store_palettized(key, width, height, &palettized);
+        }
+        Ok((width, height, palettized))
+    }
+
+    /// Key describing everything output depends on, error if any of input images cannot be read
+    fn cache_key(
+        cache: &BuildCache,
+        palette: &Palette,
+        input_images: &[String],
+        options: &ColorizeOptions,
+        output_image_file: &str,
+    ) -> Result<String, Error> {
+        let palette_bytes: Vec<u8> = palette.clone().into();
+        let template_bytes: Vec<u8> = options.template.clone().into();
+        let settings = format!(
+            "{}:{:?}:{}:{}:{:?}:{:?}:{:?}:{}",
+            env!("GIT_HASH"),
+            options.output_scale,
+            options.merge,
+            options.max_columns,
+            options.merge_layout,
+            options.pad_slot,
+            options.color_mode,
+            output_image_file
+        );
+        let image_hashes: Vec<String> = input_images
+            .iter()
+            .map(|input_image| {
+                cache
+                    .file_hash(input_image)
+                    .map_err(|err| Error::Read(input_image.to_string(), err))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut parts: Vec<&[u8]> = vec![&palette_bytes, &template_bytes, settings.as_bytes()];
+        parts.extend(image_hashes.iter().map(|hash| hash.as_bytes()));
+        Ok(BuildCache::hash(&parts))
+    }
+
+    /// File name with `suffix` added before .png extension, which is added if missing
+    pub(crate) fn with_suffix(output_image_file: &str, suffix: &str) -> String {
+        let stem = if output_image_file.to_lowercase().ends_with(".png") {
+            &output_image_file[..output_image_file.len() - ".png".len()]
+        } else {
+            output_image_file
+        };
+        format!("{stem}{suffix}.png")
+    }
+
+    /// Name of output file for input image number `counter`, when not merging
+    fn output_file_for_image(output_image_file: &str, counter: usize, input_len: usize) -> String {
+        if input_len > 1 {
+            Self::with_suffix(output_image_file, &format!("{:03}", counter))
+        } else {
+            Self::with_suffix(output_image_file, "")
+        }
+    }
+
+    /// Name of output file for palette job and screenshot (`None` when merging).
+    ///
+    /// `output_image_file` is one of job, see [`Self::output_file_for_palette`]
+    pub fn output_file(
+        options: &ColorizeOptions,
+        output_image_file: &str,
+        pal_file: &str,
+        image: Option<(usize, &str)>,
+        input_len: usize,
+    ) -> String {
+        match (&options.name_template, image) {
+            (Some(name_template), _) => name_template.render(&NameContext {
+                output_image_file,
+                pal_file,
+                image_file: image.map(|(_, image_file)| image_file),
+                counter: image.map(|(counter, _)| counter).unwrap_or(0),
+                scale: options.output_scale.unwrap_or(1),
+            }),
+            (None, Some((counter, _))) => {
+                Self::output_file_for_image(output_image_file, counter, input_len)
+            }
+            (None, None) => output_image_file.to_string(),
+        }
+    }
+
+    /// Error if any output file would be written more than once
+    fn check_collisions(
+        jobs: &[(String, String)],
+        input_images: &[String],
+        options: &ColorizeOptions,
+    ) -> Result<(), Error> {
+        let input_len = input_images.len();
+        let outputs: Vec<(String, String)> = if options.merge {
+            jobs.iter()
+                .map(|(pal_file, output)| {
+                    let output = Self::output_file(options, output, pal_file, None, input_len);
+                    (output, pal_file.clone())
+                })
+                .collect()
+        } else {
+            input_images
+                .iter()
+                .enumerate()
+                .flat_map(|(counter, input_image)| {
+                    jobs.iter().map(move |(pal_file, output)| {
+                        let image = Some((counter, input_image.as_str()));
+                        let output = Self::output_file(options, output, pal_file, image, input_len);
+                        (output, format!("{pal_file} with {input_image}"))
+                    })
+                })
+                .collect()
+        };
+        let outputs = outputs
+            .iter()
+            .map(|(output, source)| (output.as_str(), source.clone()));
+        Ok(NameTemplate::check_collisions(outputs)?)
+    }
+
+    /// Palettize, scale and encode screenshot with template palette.
+    ///
+    /// Result is the same for every palette, so it is done once and then
+    /// only PLTE chunk is replaced for each of palettes
+    fn prepare_image(
+        template: &Palette,
+        input_image: &str,
+        output_scale: usize,
+        color_mode: &ColorMode,
+        cache: Option<&BuildCache>,
+        classifier: Option<&ScreenshotClassifier>,
+    ) -> Result<Vec<u8>, Error> {
+        let (width, height, unscaled) =
+            Self::palettize_file(template, input_image, color_mode, cache, classifier)?;
+        let scaled = Self::scale_paletted_image(&unscaled, width, height, output_scale);
+        let pal: PngPalette = template.clone().into();
+        let pal: [u8; 256 * 3] = pal.into();
+        Ok(PngHelper::encode(
+            (width * output_scale) as u32,
+            (height * output_scale) as u32,
+            &pal,
+            &scaled,
+        ))
+    }
+
+    /// Write prepared image with palette applied
+    fn write_with_palette(prepared: &[u8], palette: &Palette, output_image_file: &str) {
+        let pal: PngPalette = palette.clone().into();
+        let pal: [u8; 256 * 3] = pal.into();
+        let bytes = PngHelper::replace_palette(prepared, &pal)
+            .expect("Prepared image is always paletted .png");
+        info!("Saving image file: {}", output_image_file);
+        // Name template may put outputs into directories of their own
+        if let Some(dir) = Path::new(output_image_file).parent() {
+            fs::create_dir_all(dir)
+                .unwrap_or_else(|err| panic!("Cannot create directory {:?}: {}", dir, err));
+        }
+        fs::write(output_image_file, bytes)
+            .unwrap_or_else(|err| panic!("Cannot write image file {}: {}", output_image_file, err));
+    }
+
+    /// Merge all input images into one paletted image, prepared same as in [`Self::prepare_image`]
+    ///
+    /// Images may have different sizes: each column is as wide as its widest image and each row
+    /// as high as its highest image, with images centred in their cells
+    fn prepare_merged_image(
+        template: &Palette,
+        input_images: &[String],
+        options: &ColorizeOptions,
+        classifier: Option<&ScreenshotClassifier>,
+    ) -> Option<Vec<u8>> {
+        let output_scale = options.output_scale.unwrap_or(1) as usize;
+        let layout = options.merge_layout;
+        let pad_index = options.pad_slot.map(|slot| slot as u8).unwrap_or(255);
+
+        let results: Vec<Result<_, Error>> = input_images
+            .par_iter()
+            .map(|input_image| {
+                let (width, height, unscaled) = Self::palettize_file(
+                    template,
+                    input_image,
+                    &options.color_mode,
+                    options.cache.as_deref(),
+                    classifier,
+                )?;
+                let scaled = Self::scale_paletted_image(&unscaled, width, height, output_scale);
+                Ok((width * output_scale, height * output_scale, scaled))
+            })
+            .collect();
+        // Screenshots which cannot be used are left out of merged image
+        let (input_images, scaled): (Vec<&String>, Vec<_>) = input_images
+            .iter()
+            .zip(results)
+            .filter_map(|(input_image, result)| match result {
+                Ok(scaled) => Some((input_image, scaled)),
+                Err(err) => {
+                    warn!("{err}, skipping it");
+                    None
+                }
+            })
+            .unzip();
+        if scaled.is_empty() {
+            warn!("No screenshot can be merged");
+            return None;
+        }
+        let input_len = input_images.len();
+        let columns = (options.max_columns as usize).max(1);
+        let rows = input_len.div_ceil(columns);
+        let mut column_widths = vec![0; columns];
+        let mut row_heights = vec![0; rows];
+        for (counter, (width, height, _)) in scaled.iter().enumerate() {
+            let (row, column) = layout.position(counter, rows, columns);
+            column_widths[column] = column_widths[column].max(*width);
+            row_heights[row] = row_heights[row].max(*height);
+        }
+        if scaled
+            .iter()
+            .any(|(width, height, _)| (*width, *height) != (scaled[0].0, scaled[0].1))
+        {
+            info!(
+                "Merging images of different sizes, column widths: {:?}, row heights: {:?}",
+                column_widths, row_heights
+            );
+        }
+        let merged_width: usize = column_widths.iter().sum();
+        let merged_height: usize = row_heights.iter().sum();
+        let mut merged_image_bytes = vec![pad_index; merged_width * merged_height];
+
+        for (counter, (source_width, source_height, output_image_bytes)) in
+            scaled.iter().enumerate()
+        {
+            let (row, column) = layout.position(counter, rows, columns);
+            let x = column_widths[..column].iter().sum::<usize>()
+                + (column_widths[column] - source_width) / 2;
+            let y =
+                row_heights[..row].iter().sum::<usize>() + (row_heights[row] - source_height) / 2;
+            PngHelper::copy_from_to(
+                output_image_bytes,
+                *source_width,
+                *source_height,
+                &mut merged_image_bytes,
+                merged_width,
+                merged_height,
+                x,
+                y,
+            )
+            .unwrap_or_else(|err| panic!("Cannot merge {}: {}", input_images[counter], err));
+        }
+        let pal: PngPalette = template.clone().into();
+        let pal: [u8; 256 * 3] = pal.into();
+        Some(PngHelper::encode(
+            merged_width as u32,
+            merged_height as u32,
+            &pal,
+            &merged_image_bytes,
+        ))
+    }
+
+    /// Colorize input images using single palette.
+    ///
+    /// If `only_images` is provided, then only these input images are colorized, so other
+    /// outputs are left untouched. Counters in output names are still based on all input images.
+    /// This is ignored when merging, as merged image always contains all input images.
+    ///
+    /// Returns names of output files, including ones which were up to date in cache
     pub fn color_images(
         pal_file: &str,
-        input_images: &Vec<String>,
+        input_images: &[String],
         output_image_file: &str,
-        output_scale: Option<u8>,
-        merge: bool,
-        max_columns: u8,
-        _merge_layout: MergeLayout,
-    ) {
-        debug!("Opening palette file {}", pal_file);
-        let palette = Palette::load(pal_file).unwrap();
-        let output_scale = output_scale.unwrap_or(1);
-
-        let template = Palette::default();
+        options: &ColorizeOptions,
+        only_images: Option<&HashSet<String>>,
+    ) -> Result<Vec<String>, Error> {
+        let jobs = [(pal_file.to_string(), output_image_file.to_string())];
+        Self::color_images_with_palettes(&jobs, input_images, options, only_images)
+    }
+
+    /// Warn about slots of template (palette screenshots were taken with) which cannot be told apart,
+    /// and about palettes for which that makes result only best-effort
+    fn report_ambiguity(template: &Palette, palettes: &[(Palette, &str)], pal_files: &[&str]) {
+        let close: Vec<(usize, usize)> = Self::template_conflicts(template)
+            .into_iter()
+            .filter(|(a, b)| template.slot(*a) != template.slot(*b))
+            .collect();
+        for (a, b) in &close {
+            warn!(
+                "{} and {} have close colors, some pixels of screenshots may be mapped to wrong one",
+                Palette::slot_name(*a),
+                Palette::slot_name(*b)
+            );
+        }
+        let shared = template.shared_slots();
+        if shared.is_empty() {
+            return;
+        }
+        for slots in &shared {
+            warn!(
+                "{} share color {} on screenshots, it is mapped to {}",
+                slots
+                    .iter()
+                    .map(|slot| Palette::slot_name(*slot))
+                    .join(", "),
+                template.slot(slots[0]).to_hex(),
+                Palette::slot_name(slots[0])
+            );
+        }
+        for ((palette, _), pal_file) in palettes.iter().zip(pal_files) {
+            let wrong = shared
+                .iter()
+                .flat_map(|slots| {
+                    slots[1..]
+                        .iter()
+                        .filter(|slot| palette.slot(**slot) != palette.slot(slots[0]))
+                        .map(|slot| {
+                            format!(
+                                "{} shown as {}",
+                                Palette::slot_name(*slot),
+                                Palette::slot_name(slots[0])
+                            )
+                        })
+                })
+                .join(", ");
+            if wrong.is_empty() {
+                info!("{pal_file}: exact, shared colors are the same in it as well");
+            } else {
+                warn!("{pal_file}: best-effort, {wrong}");
+            }
+        }
+    }
+
+    /// Screenshots already colorized with known palette (built-in or one of palettes used)
+    /// cannot be colorized, ones which do not look like taken with template palette are only warned about
+    fn check_screenshot(
+        classifier: &ScreenshotClassifier,
+        input_image: &str,
+        image: &DynamicImage,
+    ) -> Result<(), Error> {
+        let kind = classifier.classify(image);
+        debug!("Screenshot {input_image} is {kind}");
+        match kind {
+            ScreenshotKind::Template => Ok(()),
+            ScreenshotKind::Colorized(_) | ScreenshotKind::OtherTemplate(_) => {
+                Err(Error::NotTemplate(input_image.to_string(), kind))
+            }
+            ScreenshotKind::Unknown(_) => {
+                warn!("{input_image} does not look like template screenshot ({kind}), colors may be wrong");
+                Ok(())
+            }
+        }
+    }
+
+    /// Colorize input images using multiple palettes, `jobs` are pairs of palette file
+    /// and output image file (as passed to [`Self::color_images`]).
+    ///
+    /// Each screenshot is decoded, palettized and scaled only once, then for each palette
+    /// only palette of resulting .png is replaced. Screenshots are processed in batches,
+    /// so memory usage does not grow with number of screenshots.
+    ///
+    /// Screenshots and palettes which cannot be used are skipped with warning.
+    ///
+    /// Returns names of output files, including ones which were up to date in cache
+    pub fn color_images_with_palettes(
+        jobs: &[(String, String)],
+        input_images: &[String],
+        options: &ColorizeOptions,
+        only_images: Option<&HashSet<String>>,
+    ) -> Result<Vec<String>, Error> {
+        let output_scale = options.output_scale.unwrap_or(1) as usize;
+        let template = options.template.clone();
         debug!(
             "Template palette loaded \n{}",
             template.as_ansi(AsAnsiType::ColorValueDec)
@@ -160,170 +670,342 @@ impl ImageHandler {
             "All input files, including globbed results:\n{:#?}",
             &input_images
         );
-        let input_len = input_images.len();
-        let (input_width, input_height) = PngHelper::get_size(&input_images[0]);
-        let max_columns = max_columns as usize;
-        let merged_width: usize = max_columns * input_width as usize * output_scale as usize;
-        let no_rows = (input_len as f32 / max_columns as f32).ceil() as usize;
-        let merged_height = no_rows * input_height as usize * output_scale as usize;
-        let mut merged_image_bytes = if merge {
-            Some(vec![255_u8; merged_width * merged_height])
+        // Screenshots are checked when they are decoded for colorizing,
+        // skipped ones still count, so counters in output names do not depend on them
+        let classifier = if let ColorMode::Template = options.color_mode {
+            let pal_files: Vec<String> =
+                jobs.iter().map(|(pal_file, _)| pal_file.clone()).collect();
+            Some(ScreenshotClassifier::new(&template, &pal_files))
         } else {
             None
         };
+        let classifier = classifier.as_ref();
+        let input_len = input_images.len();
+        Self::check_collisions(jobs, &input_images, options)?;
+        let cache = options.cache.as_deref();
+        // Palettes which cannot be loaded are skipped, so the rest is still written
+        let (palettes, jobs): (Vec<(Palette, &str)>, Vec<_>) = jobs
+            .par_iter()
+            .filter_map(|job| {
+                let (pal_file, output_image_file) = job;
+                debug!("Opening palette file {}", pal_file);
+                let palette = Palette::load(pal_file)
+                    .map_err(|err| warn!("Skipping palette {pal_file}: {err}"))
+                    .ok()?;
+                let palette = if options.quantize_555 {
+                    palette.lint_555(pal_file);
+                    palette.quantize_555()
+                } else {
+                    palette
+                };
+                Some(((palette, output_image_file.as_str()), job))
+            })
+            .unzip();
+        let jobs = jobs.as_slice();
+        if let ColorMode::Template = options.color_mode {
+            let pal_files: Vec<&str> = jobs.iter().map(|(pal_file, _)| pal_file.as_str()).collect();
+            Self::report_ambiguity(&template, &palettes, &pal_files);
+        }
+
+        if options.merge {
+            // (palette, output file, cache key) for outputs which need to be written
+            let merged: Vec<(&Palette, String)> = palettes
+                .iter()
+                .zip(jobs)
+                .map(|((palette, output_image_file), (pal_file, _))| {
+                    let output_image_file =
+                        Self::output_file(options, output_image_file, pal_file, None, input_len);
+                    (palette, output_image_file)
+                })
+                .collect();
+            let pending: Vec<_> = merged
+                .par_iter()
+                .map(|(palette, output_image_file)| {
+                    // Unreadable screenshot is left out of merged image (with warning when
+                    // preparing it), output is then just not cached
+                    let key = cache.and_then(|cache| {
+                        Self::cache_key(cache, palette, &input_images, options, output_image_file)
+                            .map_err(|err| debug!("Not caching {output_image_file}: {err}"))
+                            .ok()
+                    });
+                    (*palette, output_image_file.as_str(), key)
+                })
+                .filter(|(_, output_image_file, key)| {
+                    let up_to_date = matches!((cache, key), (Some(cache), Some(key)) if cache.is_up_to_date(output_image_file, key));
+                    if up_to_date {
+                        info!("Merged image file {} is up to date", output_image_file);
+                    }
+                    !up_to_date
+                })
+                .collect();
+            if !pending.is_empty() {
+                let Some(prepared) =
+                    Self::prepare_merged_image(&template, &input_images, options, classifier)
+                else {
+                    return Ok(Vec::new());
+                };
+                pending
+                    .par_iter()
+                    .for_each(|(palette, output_image_file, key)| {
+                        Self::write_with_palette(&prepared, palette, output_image_file);
+                        if let (Some(cache), Some(key)) = (cache, key) {
+                            cache.store_output(output_image_file, key);
+                        }
+                    });
+            }
+            return Ok(merged
+                .into_iter()
+                .map(|(_, output_image_file)| output_image_file)
+                .collect());
+        }
 
-        input_images
+        let selected: Vec<(usize, &String)> = input_images
             .iter()
             .enumerate()
-            .for_each(|(counter, input_image)| {
-                debug!("Opening image file {}", input_image);
-                let image = Reader::open(input_image)
-                    .unwrap_or_else(|_| panic!("Cannot open image file {}", input_image))
-                    .decode()
-                    .unwrap_or_else(|_| panic!("Cannot decode image file {}", input_image));
-                info!("Opened image file {}", input_image);
-                let output_image_bytes = {
-                    let unscaled = Self::palettize_image(template.clone(), &image);
-                    Self::scale_paletted_image(
-                        &unscaled,
-                        image.width() as usize,
-                        image.height() as usize,
-                        output_scale as usize,
+            .filter(|(_, input_image)| only_images.is_none_or(|only| only.contains(*input_image)))
+            .collect();
+        // Batch screenshots, so scaled index buffers of whole batch fit in memory budget
+        let mut batches: Vec<Vec<(usize, &String)>> = Vec::new();
+        let mut batch_size = 0;
+        for (counter, input_image) in selected {
+            // Unreadable screenshots are reported and skipped when preparing them
+            let (width, height) = PngHelper::get_size(input_image).unwrap_or((0, 0));
+            let size = width as usize * height as usize * output_scale * output_scale;
+            if batch_size + size > Self::MEMORY_BUDGET || batches.is_empty() {
+                batches.push(Vec::new());
+                batch_size = 0;
+            }
+            batch_size += size;
+            batches.last_mut().unwrap().push((counter, input_image));
+        }
+
+        let mut output_files = Vec::new();
+        for batch in batches {
+            debug!("Processing batch of {} screenshot(s)", batch.len());
+            // (screenshot, palette, output file, cache key) which need to be written
+            let pending: Vec<_> = batch
+                .par_iter()
+                .flat_map_iter(|(counter, input_image)| {
+                    palettes.iter().zip(jobs).map(
+                        move |((palette, output_image_file), (pal_file, _))| {
+                            let output_image_file = Self::output_file(
+                                options,
+                                output_image_file,
+                                pal_file,
+                                Some((*counter, input_image.as_str())),
+                                input_len,
+                            );
+                            (*input_image, palette, output_image_file)
+                        },
                     )
-                };
-                let output_image_file = if output_image_file.to_lowercase().ends_with(".png") {
-                    output_image_file.to_string()
-                } else {
-                    format!("{}.png", output_image_file)
-                };
-                let output_image_file = if input_len > 1 && !merge {
-                    // TODO: This will not work correctly in edge case when user will use e.g. 'test.png.png' 🤷
-                    // We should only replace last match
-                    output_image_file
-                        .to_lowercase()
-                        .replace(".png", &format!("{:03}.png", counter))
-                } else {
-                    output_image_file
-                };
-                if merge {
-                    if let Some(merged_image_bytes) = &mut merged_image_bytes {
-                        let source_width = (image.width() * output_scale as u32) as usize;
-                        let source_height = (image.height() * output_scale as u32) as usize;
-                        let y = counter / max_columns;
-                        let x = counter % max_columns;
-                        let y = y * source_height;
-                        let x = x * source_width;
-                        PngHelper::copy_from_to(
-                            &output_image_bytes,
-                            // I like Rust but I don't like constant type conversions, maybe this is my fault though
-                            source_width,
-                            source_height,
-                            merged_image_bytes,
-                            merged_width,
-                            merged_height,
-                            x,
-                            y,
-                        )
-                    } else {
-                        // this will never happen
-                        panic!("If this happened you should probably play lottery")
+                })
+                .map(|(input_image, palette, output_image_file)| {
+                    let key = cache
+                        .map(|cache| {
+                            Self::cache_key(
+                                cache,
+                                palette,
+                                std::slice::from_ref(input_image),
+                                options,
+                                &output_image_file,
+                            )
+                        })
+                        .transpose();
+                    (input_image, palette, output_image_file, key)
+                })
+                .collect();
+            // Screenshots which cannot be used are skipped, so the rest is still written
+            let mut failed: HashSet<&String> = HashSet::new();
+            let pending: Vec<_> = pending
+                .into_iter()
+                .filter_map(|(input_image, palette, output_image_file, key)| match key {
+                    Ok(key) => Some((input_image, palette, output_image_file, key)),
+                    Err(err) => {
+                        if failed.insert(input_image) {
+                            warn!("{err}, skipping it");
+                        }
+                        None
                     }
-                } else {
-                    let pal: PngPalette = palette.clone().into();
-                    let pal: [u8; 256 * 3] = pal.into();
-                    info!("Saving image file: {}", &output_image_file);
-                    PngHelper::save(
-                        &output_image_file,
-                        image.width() * output_scale as u32,
-                        image.height() * output_scale as u32,
-                        &pal,
-                        &output_image_bytes,
+                })
+                .collect();
+            let outputs: Vec<(&String, String)> = pending
+                .iter()
+                .map(|(image, _, output, _)| (*image, output.clone()))
+                .collect();
+            let pending: Vec<_> = pending
+                .into_iter()
+                .filter(|(_, _, output_image_file, key)| {
+                    let up_to_date = matches!((cache, key), (Some(cache), Some(key)) if cache.is_up_to_date(output_image_file, key));
+                    if up_to_date {
+                        debug!("Image file {} is up to date", output_image_file);
+                    }
+                    !up_to_date
+                })
+                .collect();
+            let needed: HashSet<&String> = pending.iter().map(|(image, _, _, _)| *image).collect();
+            let results: Vec<(&String, Result<Vec<u8>, Error>)> = needed
+                .into_par_iter()
+                .map(|input_image| {
+                    let prepared = Self::prepare_image(
+                        &template,
+                        input_image,
+                        output_scale,
+                        &options.color_mode,
+                        cache,
+                        classifier,
                     );
+                    (input_image, prepared)
+                })
+                .collect();
+            let mut prepared: HashMap<&String, Vec<u8>> = HashMap::new();
+            for (input_image, result) in results {
+                match result {
+                    Ok(bytes) => {
+                        prepared.insert(input_image, bytes);
+                    }
+                    Err(err) => {
+                        warn!("{err}, skipping it");
+                        failed.insert(input_image);
+                    }
                 }
-            });
-        if merge {
-            if let Some(merged_image_bytes) = &merged_image_bytes {
-                let pal: PngPalette = palette.clone().into();
-                let pal: [u8; 256 * 3] = pal.into();
-                info!("Saving merged image file: {}", &output_image_file);
-                PngHelper::save(
-                    output_image_file,
-                    merged_width as u32,
-                    merged_height as u32,
-                    &pal,
-                    merged_image_bytes,
-                );
-            } else {
-                // this will never happen
-                panic!("If this happened you should probably play lottery")
             }
+            output_files.extend(
+                outputs
+                    .into_iter()
+                    .filter(|(image, _)| !failed.contains(image))
+                    .map(|(_, output)| output),
+            );
+            pending
+                .iter()
+                .filter(|(input_image, _, _, _)| !failed.contains(input_image))
+                .collect::<Vec<_>>()
+                .par_iter()
+                .for_each(|(input_image, palette, output_image_file, key)| {
+                    Self::write_with_palette(&prepared[input_image], palette, output_image_file);
+                    if let (Some(cache), Some(key)) = (cache, key) {
+                        cache.store_output(output_image_file, key);
+                    }
+                });
+        }
+        Ok(output_files)
+    }
+
+    /// Output file name used for given palette, when `palettes_count` palettes are used at once.
+    ///
+    /// With name template it is left as is, as template is filled for each output separately
+    pub fn output_file_for_palette(
+        options: &ColorizeOptions,
+        output_image_file: &str,
+        pal_file: &str,
+        palettes_count: usize,
+    ) -> String {
+        if palettes_count == 1 || options.name_template.is_some() {
+            return output_image_file.to_string();
         }
+        let pal_name_escaped = pal_file.replace('/', "$").replace(':', "_");
+        Self::with_suffix(output_image_file, &pal_name_escaped)
     }
 
+    /// Pairs of palette file and image shown for it in HTML: merged image, or one of first screenshot
+    /// which was written, as screenshots which cannot be colorized are skipped.
+    ///
+    /// Input images are already globbed
+    pub fn html_images(
+        jobs: &[(String, String)],
+        input_images: &[String],
+        options: &ColorizeOptions,
+    ) -> Vec<(String, String)> {
+        let input_len = input_images.len();
+        jobs.iter()
+            .map(|(pal_file, output_image_file)| {
+                let output = |image| {
+                    Self::output_file(options, output_image_file, pal_file, image, input_len)
+                };
+                let image = match options.merge {
+                    true => output(None),
+                    false => input_images
+                        .iter()
+                        .enumerate()
+                        .map(|(counter, image)| output(Some((counter, image.as_str()))))
+                        .find(|image| Path::new(image).is_file())
+                        .unwrap_or_else(|| {
+                            output(input_images.first().map(|image| (0, image.as_str())))
+                        }),
+                };
+                (pal_file.clone(), image)
+            })
+            .collect()
+    }
+
+    /// Colorize input images using each of palettes.
+    ///
+    /// Returns pairs of palette file and its output image file
     pub fn use_palettes_to_color_images(
-        pal_files: &Vec<String>,
-        input_images: &Vec<String>,
+        pal_files: &[String],
+        input_images: &[String],
         output_image_file: &str,
-        output_scale: Option<u8>,
-        merge: bool,
-        max_columns: u8,
-        merge_layout: MergeLayout,
-        generate_html: bool,
-    ) {
+        options: &ColorizeOptions,
+    ) -> Result<Vec<(String, String)>, Error> {
         let pal_files = Helpers::glob_paths(pal_files);
-        if pal_files.len() == 1 {
-            return Self::color_images(
-                &pal_files[0],
-                input_images,
-                output_image_file,
-                output_scale,
-                merge,
-                max_columns,
-                merge_layout,
-            );
-        }
         let pal_images: Vec<_> = pal_files
-            .par_iter()
+            .iter()
             .map(|pal| {
-                let pal_name_escaped = pal.replace('/', "$");
                 let output_image_file =
-                    output_image_file.replace(".png", &format!("{}.png", pal_name_escaped));
-                Self::color_images(
-                    pal,
-                    input_images,
-                    &output_image_file,
-                    output_scale,
-                    merge,
-                    max_columns,
-                    merge_layout,
-                );
+                    Self::output_file_for_palette(options, output_image_file, pal, pal_files.len());
                 (pal.clone(), output_image_file)
             })
             .collect();
-        if generate_html {
-            let mut context = Context::new();
-            context.insert("version", env!("GIT_HASH_SHORT"));
-            let mut palletes: Vec<HashMap<_, _>> = Vec::new();
-            // TODO: obviously un-hardcode this
-            let html_file = "output.html";
-            info!("Generating HTML file '{html_file}'...");
-            debug!("Output images = {pal_images:#?}");
-            pal_images.iter().for_each(|(pal, image)| {
-                let pal_name = if let Some(last_slash) = pal.rfind('/') {
-                    &pal[last_slash + 1..pal.len()]
-                } else {
-                    pal
-                };
-                palletes.push(HashMap::from([
-                    ("name", pal_name),
-                    ("path", pal),
-                    ("image", image),
-                ]));
-            });
-            context.insert("palettes", &palletes);
-            let rendered = TEMPLATES.render("index.html", &context).unwrap();
-            std::fs::write(html_file, rendered).expect("Cannot create HTML file");
-            info!("Created HTML file '{html_file}'")
+        Self::color_images_with_palettes(&pal_images, input_images, options, None)?;
+        if options.generate_html && pal_files.len() > 1 {
+            let input_images = Helpers::glob_paths(input_images);
+            Self::generate_html(&Self::html_images(&pal_images, &input_images, options));
+        }
+        Ok(pal_images)
+    }
+
+    /// Name, path and metadata of palette for HTML templates, missing values are empty
+    pub fn palette_info(pal: &str) -> HashMap<&'static str, String> {
+        let pal_name = if let Some(last_slash) = pal.rfind('/') {
+            &pal[last_slash + 1..pal.len()]
+        } else {
+            pal
+        };
+        let metadata = PaletteMetadata::load(pal);
+        let mut map = HashMap::from([
+            (
+                "name",
+                metadata.name.unwrap_or_else(|| pal_name.to_string()),
+            ),
+            ("path", pal.to_string()),
+        ]);
+        for (key, value) in [
+            ("author", metadata.author),
+            ("license", metadata.license),
+            ("credit", metadata.credit),
+            ("url", metadata.url),
+            ("description", metadata.description),
+        ] {
+            map.insert(key, value.unwrap_or_default());
         }
+        map
+    }
+
+    pub fn generate_html(pal_images: &[(String, String)]) {
+        let mut context = Context::new();
+        context.insert("version", env!("GIT_HASH_SHORT"));
+        let mut palletes: Vec<HashMap<_, String>> = Vec::new();
+        // TODO: obviously un-hardcode this
+        let html_file = "output.html";
+        info!("Generating HTML file '{html_file}'...");
+        debug!("Output images = {pal_images:#?}");
+        pal_images.iter().for_each(|(pal, image)| {
+            let mut map = Self::palette_info(pal);
+            map.insert("image", image.to_string());
+            palletes.push(map);
+        });
+        context.insert("palettes", &palletes);
+        let rendered = TEMPLATES.render("index.html", &context).unwrap();
+        std::fs::write(html_file, rendered).expect("Cannot create HTML file");
+        info!("Created HTML file '{html_file}'")
     }
 }
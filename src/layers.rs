@@ -0,0 +1,276 @@
+use std::fs;
+use std::path::Path;
+
+use itertools::Itertools;
+use log::{info, warn};
+
+use crate::image_handler::{self, ColorMode, ImageHandler};
+use crate::naming::{self, NameTemplate};
+use crate::palette::{Color, Palette};
+use crate::png_helper::{PngHelper, PngPalette};
+
+/// Layer of Pocket palette, as slots are grouped in it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layer {
+    Bg,
+    Obj0,
+    Obj1,
+    Window,
+    LcdOff,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 5] = [
+        Layer::Bg,
+        Layer::Obj0,
+        Layer::Obj1,
+        Layer::Window,
+        Layer::LcdOff,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layer::Bg => "bg",
+            Layer::Obj0 => "obj0",
+            Layer::Obj1 => "obj1",
+            Layer::Window => "window",
+            Layer::LcdOff => "lcd_off",
+        }
+    }
+
+    pub fn slots(&self) -> std::ops::Range<usize> {
+        match self {
+            Layer::Bg => 0..4,
+            Layer::Obj0 => 4..8,
+            Layer::Obj1 => 8..12,
+            Layer::Window => 12..16,
+            Layer::LcdOff => 16..17,
+        }
+    }
+}
+
+/// Which template slot each pixel of screenshot is, so we know what layers game uses
+pub struct LayerAnalysis {
+    pub width: usize,
+    pub height: usize,
+    indexes: Vec<u8>,
+    /// Pixel count per palette index, index 255 is for colors not found in template
+    counts: [usize; 256],
+}
+
+impl LayerAnalysis {
+    /// Used around layers on exploded sheet, and for pixels of other layers
+    const BACKGROUND: Color = [0x40, 0x40, 0x50];
+    /// Space between layers on exploded sheet, before scaling
+    const GAP: usize = 4;
+
+    pub fn new(template: &Palette, input_image: &str) -> Result<Self, image_handler::Error> {
+        let (width, height, indexes) =
+            ImageHandler::palettize_file(template, input_image, &ColorMode::Template, None, None)?;
+        let mut counts = [0; 256];
+        for index in &indexes {
+            counts[*index as usize] += 1;
+        }
+        Ok(Self {
+            width,
+            height,
+            indexes,
+            counts,
+        })
+    }
+
+    pub fn slot_count(&self, slot: usize) -> usize {
+        self.counts[slot]
+    }
+
+    pub fn layer_count(&self, layer: Layer) -> usize {
+        layer.slots().map(|slot| self.counts[slot]).sum()
+    }
+
+    /// Pixels not matching any template color
+    pub fn unknown_count(&self) -> usize {
+        self.counts[255]
+    }
+
+    /// Table with pixel count and percentage for every slot and layer
+    pub fn report(&self) -> String {
+        let total = (self.width * self.height).max(1) as f32;
+        let percent = |count: usize| count as f32 / total * 100.0;
+        let mut report = String::new();
+        for layer in Layer::ALL {
+            let count = self.layer_count(layer);
+            report += &format!(
+                "{:<10} {:>8} {:>6.2}%{}\n",
+                layer.name(),
+                count,
+                percent(count),
+                if count == 0 { "  (not used)" } else { "" }
+            );
+            if layer.slots().len() > 1 {
+                for slot in layer.slots() {
+                    report += &format!(
+                        "  {:<8} {:>8} {:>6.2}%\n",
+                        Palette::slot_name(slot),
+                        self.counts[slot],
+                        percent(self.counts[slot])
+                    );
+                }
+            }
+        }
+        if self.unknown_count() > 0 {
+            report += &format!(
+                "{:<10} {:>8} {:>6.2}%\n",
+                "unknown",
+                self.unknown_count(),
+                percent(self.unknown_count())
+            );
+        }
+        report
+    }
+
+    /// Palette index buffer where only pixels of layer are kept, others point to unused index
+    fn layer_indexes(&self, layer: Layer) -> Vec<u8> {
+        let slots = layer.slots();
+        self.indexes
+            .iter()
+            .map(|index| match slots.contains(&(*index as usize)) {
+                true => *index,
+                false => 255,
+            })
+            .collect()
+    }
+
+    /// Black and white mask for each layer, named `<prefix>_<layer>.png`
+    pub fn write_masks(&self, prefix: &str, scale: usize) -> Vec<String> {
+        let mut pal = PngPalette::new();
+        pal.push([0, 0, 0]);
+        pal.push([255, 255, 255]);
+        let pal: [u8; 256 * 3] = pal.into();
+        Layer::ALL
+            .iter()
+            .map(|layer| {
+                let slots = layer.slots();
+                let mask: Vec<u8> = self
+                    .indexes
+                    .iter()
+                    .map(|index| slots.contains(&(*index as usize)) as u8)
+                    .collect();
+                let mask =
+                    ImageHandler::scale_paletted_image(&mask, self.width, self.height, scale);
+                let file_name = format!("{prefix}_{}.png", layer.name());
+                PngHelper::save(
+                    &file_name,
+                    (self.width * scale) as u32,
+                    (self.height * scale) as u32,
+                    &pal,
+                    &mask,
+                );
+                file_name
+            })
+            .collect()
+    }
+
+    /// All layers side by side, each with only its own pixels in template colors
+    pub fn write_exploded(&self, template: &Palette, file_name: &str, scale: usize) {
+        let layers = Layer::ALL.len();
+        let sheet_width = self.width * layers + Self::GAP * (layers - 1);
+        let mut sheet = vec![255_u8; sheet_width * self.height];
+        for (position, layer) in Layer::ALL.iter().enumerate() {
+            PngHelper::copy_from_to(
+                &self.layer_indexes(*layer),
+                self.width,
+                self.height,
+                &mut sheet,
+                sheet_width,
+                self.height,
+                position * (self.width + Self::GAP),
+                0,
+            )
+            .expect("Layer does not fit in sheet");
+        }
+        let sheet = ImageHandler::scale_paletted_image(&sheet, sheet_width, self.height, scale);
+        let mut pal: PngPalette = template.clone().into();
+        pal.set(255, Self::BACKGROUND);
+        let pal: [u8; 256 * 3] = pal.into();
+        PngHelper::save(
+            file_name,
+            (sheet_width * scale) as u32,
+            (self.height * scale) as u32,
+            &pal,
+            &sheet,
+        );
+    }
+
+    /// Analyze screenshots, writing masks and exploded sheet of each into output directory.
+    ///
+    /// Outputs are named after screenshots, so screenshots with the same name (from different
+    /// folders) are reported as error before anything is written.
+    ///
+    /// Returns analyses, in the same order as input images. Screenshots which cannot be read
+    /// are skipped with warning
+    pub fn analyze_all(
+        template: &Palette,
+        input_images: &[String],
+        output_dir: &str,
+        scale: usize,
+    ) -> Result<Vec<Self>, naming::Error> {
+        let prefixes: Vec<String> = input_images
+            .iter()
+            .map(|input_image| {
+                let stem = Path::new(input_image)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                Path::new(output_dir)
+                    .join(stem)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        let exploded: Vec<String> = prefixes
+            .iter()
+            .map(|prefix| format!("{prefix}_exploded.png"))
+            .collect();
+        NameTemplate::check_collisions(
+            exploded
+                .iter()
+                .map(String::as_str)
+                .zip(input_images.iter().cloned()),
+        )?;
+        fs::create_dir_all(output_dir)
+            .unwrap_or_else(|err| panic!("Cannot create directory {output_dir}: {err}"));
+        Ok(input_images
+            .iter()
+            .zip(prefixes.iter().zip(&exploded))
+            .filter_map(|(input_image, (prefix, exploded))| {
+                let analysis = match Self::new(template, input_image) {
+                    Ok(analysis) => analysis,
+                    Err(err) => {
+                        warn!("{err}, skipping it");
+                        return None;
+                    }
+                };
+                let masks = analysis.write_masks(prefix, scale);
+                analysis.write_exploded(template, exploded, scale);
+                info!(
+                    "{input_image} ({}x{}):\n{}Written {}, {}",
+                    analysis.width,
+                    analysis.height,
+                    analysis.report(),
+                    masks.join(", "),
+                    exploded
+                );
+                Some(analysis)
+            })
+            .collect())
+    }
+
+    /// Names of layers used (or not used) by any of analyzed screenshots
+    pub fn used_layers(analyses: &[Self], used: bool) -> String {
+        Layer::ALL
+            .iter()
+            .filter(|layer| analyses.iter().any(|a| a.layer_count(**layer) > 0) == used)
+            .map(|layer| layer.name())
+            .join(", ")
+    }
+}
@@ -1,5 +1,24 @@
+pub mod cache;
+pub mod caption;
 pub mod cli;
+pub mod config;
+pub mod contact_sheet;
+pub mod editor;
+pub mod emulator_formats;
+pub mod gallery;
+pub mod gbc;
 pub mod helpers;
 pub mod image_handler;
+pub mod layers;
+pub mod library;
+pub mod metadata;
+pub mod naming;
 pub mod palette;
 pub mod png_helper;
+pub mod pocket;
+pub mod screenshot;
+pub mod server;
+pub mod sgb;
+pub mod stats;
+pub mod terminal_preview;
+pub mod watch;
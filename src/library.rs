@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::Path;
+
+use glob::Pattern;
+use log::info;
+
+use crate::gbc::GbcCompatPalette;
+use crate::metadata::PaletteMetadata;
+use crate::palette::{hex_colors, Palette};
+
+/// Palette shipped with the binary, usable as `builtin:<name>` anywhere .pal file is accepted
+#[derive(Debug, Clone)]
+pub struct BuiltinPalette {
+    pub name: String,
+    pub description: String,
+    pub palette: Palette,
+}
+
+impl BuiltinPalette {
+    /// Tags are taken from palette group.
+    ///
+    /// Only palettes of hardware and boot ROMs are shipped, so there is no author or license to credit
+    pub fn metadata(&self) -> PaletteMetadata {
+        let group = match self.name.as_str() {
+            "template" => "template",
+            name if name.starts_with("gbc_") => "gbc",
+            name if name.starts_with("sgb_") => "sgb",
+            _ => "hardware",
+        };
+        PaletteMetadata {
+            name: Some(self.name.clone()),
+            description: Some(self.description.clone()),
+            tags: vec!["builtin".to_string(), group.to_string()],
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Library;
+
+impl Library {
+    pub const PREFIX: &'static str = "builtin:";
+
+    pub fn all() -> Vec<BuiltinPalette> {
+        let builtin = |name: &str, description: &str, palette: Palette| BuiltinPalette {
+            name: name.to_string(),
+            description: description.to_string(),
+            palette,
+        };
+        let shades = |name: &str, description: &str, shades: [[u8; 3]; 4]| {
+            builtin(name, description, Palette::from_shades(shades, shades[0]))
+        };
+        let mut all = vec![
+            builtin(
+                "template",
+                "Template for taking screenshots, same as create-template-pal",
+                Palette::default(),
+            ),
+            shades(
+                "dmg",
+                "Original Game Boy (DMG) pea-green",
+                hex_colors!(0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F),
+            ),
+            shades(
+                "pocket",
+                "Game Boy Pocket grey",
+                hex_colors!(0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F),
+            ),
+            shades(
+                "light",
+                "Game Boy Light with backlight on",
+                hex_colors!(0x00B581, 0x009A71, 0x00694A, 0x004F3B),
+            ),
+        ];
+        all.extend(GbcCompatPalette::boot_rom().iter().map(|palette| {
+            builtin(
+                &format!("gbc_{}", palette.name),
+                &format!(
+                    "Game Boy Color boot ROM palette, selected by holding {} during boot",
+                    palette.name.replace('_', "+").to_uppercase()
+                ),
+                palette.to_palette(),
+            )
+        }));
+        // Palettes selectable on Super Game Boy with 1-A to 1-H codes
+        let sgb = [
+            ("1a", hex_colors!(0xF8E8C8, 0xD89048, 0xA82820, 0x301850)),
+            ("1b", hex_colors!(0xD8D8C0, 0xC8B070, 0xB05010, 0x000000)),
+            ("1c", hex_colors!(0xF8C0F8, 0xE89850, 0x983860, 0x383898)),
+            ("1d", hex_colors!(0xF8F8A8, 0xC08048, 0xF80000, 0x501800)),
+            ("1e", hex_colors!(0xF8D8B0, 0x78C078, 0x688840, 0x583820)),
+            ("1f", hex_colors!(0xD8E8F8, 0xE08850, 0xA80000, 0x004010)),
+            ("1g", hex_colors!(0x000050, 0x00A0E8, 0x787800, 0xF8F858)),
+            ("1h", hex_colors!(0xF8E8E0, 0xF8B888, 0x804000, 0x301800)),
+        ];
+        all.extend(sgb.into_iter().map(|(code, colors)| {
+            shades(
+                &format!("sgb_{code}"),
+                &format!("Super Game Boy palette {}", code.to_uppercase()),
+                colors,
+            )
+        }));
+        all
+    }
+
+    /// Built-in palette by name, without `builtin:` prefix
+    pub fn get(name: &str) -> Option<BuiltinPalette> {
+        Self::all().into_iter().find(|builtin| builtin.name == name)
+    }
+
+    pub fn is_builtin(path: &str) -> bool {
+        path.starts_with(Self::PREFIX)
+    }
+
+    /// Names (with `builtin:` prefix) of built-in palettes matching glob pattern, e.g. `builtin:gbc_*`
+    pub fn glob(pattern: &str) -> Vec<String> {
+        let name_pattern = pattern.strip_prefix(Self::PREFIX).unwrap_or(pattern);
+        let name_pattern = Pattern::new(name_pattern)
+            .unwrap_or_else(|_| panic!("Incorrect glob pattern: {}", pattern));
+        Self::all()
+            .into_iter()
+            .filter(|builtin| name_pattern.matches(&builtin.name))
+            .map(|builtin| format!("{}{}", Self::PREFIX, builtin.name))
+            .collect()
+    }
+
+    /// Save built-in palettes as .pal files named after them
+    pub fn export(builtins: &[BuiltinPalette], output_dir: &str) {
+        fs::create_dir_all(output_dir)
+            .unwrap_or_else(|err| panic!("Cannot create directory {output_dir}: {err}"));
+        for builtin in builtins {
+            let file_name = Path::new(output_dir).join(format!("{}.pal", builtin.name));
+            let file_name = file_name.to_string_lossy();
+            info!(
+                "Exporting built-in palette {} to {}",
+                builtin.name, file_name
+            );
+            builtin.palette.save(&file_name);
+        }
+    }
+}
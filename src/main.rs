@@ -1,15 +1,41 @@
 use std::fs;
 use std::path::Path;
+use std::process::exit;
+use std::sync::Arc;
 
-use analogue_pal_tool::palette::{AsAnsiVec, Palette};
+use analogue_pal_tool::layers::LayerAnalysis;
+use analogue_pal_tool::library::{BuiltinPalette, Library};
+use analogue_pal_tool::palette::{AsAnsiType, AsAnsiVec, ColorExt, Palette};
 
-use analogue_pal_tool::cli::{Cli, ColorizeImage, Commands, CreateTemplatePal, Display};
-use analogue_pal_tool::image_handler::ImageHandler;
+use analogue_pal_tool::cache::BuildCache;
+use analogue_pal_tool::cli::{
+    AnalyzeLayers, CheckScreenshots, Cli, ColorizeImage, Commands, ContactSheet, Convert,
+    ConvertFolder, CreateTemplatePal, Display, Edit, ExportBuiltin, ImportSgb, Init, LibrarySearch,
+    Lint, Pocket, PocketCommands, PocketImportScreenshots, PocketInstall, PocketList, PocketSync,
+    Preview, Run, Serve, Stats,
+};
+use analogue_pal_tool::config::ProjectConfig;
+use analogue_pal_tool::contact_sheet;
+use analogue_pal_tool::editor::Editor;
+use analogue_pal_tool::emulator_formats::Converter;
+use analogue_pal_tool::gallery::Gallery;
+use analogue_pal_tool::gbc::GbcCompatPalette;
+use analogue_pal_tool::helpers::Helpers;
+use analogue_pal_tool::image_handler::{ColorMode, ColorizeOptions, ImageHandler};
+use analogue_pal_tool::metadata::PaletteMetadata;
+use analogue_pal_tool::pocket;
+use analogue_pal_tool::screenshot::{ScreenshotClassifier, ScreenshotKind};
+use analogue_pal_tool::server::Server;
+use analogue_pal_tool::sgb::SgbPalettes;
+use analogue_pal_tool::stats::{self, StatsFormat};
+use analogue_pal_tool::terminal_preview::TerminalPreview;
+use analogue_pal_tool::watch::Watch;
 use chrono::Local;
 use clap::Parser;
 use colored::Colorize;
+use itertools::Itertools;
 
-use log::{debug, info, warn, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
 
 fn setup_logging(level: LevelFilter) {
     fern::Dispatch::new()
@@ -32,67 +58,611 @@ fn setup_logging(level: LevelFilter) {
         // Output to stdout
         .chain(std::io::stdout())
         // Output to a log file
-        .chain(
-            fern::log_file(format!("{}.log", env!("CARGO_PKG_NAME")))
-                .expect("Cannot setup logging to file"),
-        )
+        .chain(fern::log_file(Helpers::LOG_FILE).expect("Cannot setup logging to file"))
         // Apply the configuration
         .apply()
         .expect("Cannot setup logging");
 }
 
+/// Template palette given by user or default one, exits if it cannot be used as template
+fn load_template(template: Option<&str>) -> Palette {
+    let Some(template_file) = template else {
+        return Palette::default();
+    };
+    let template = Palette::load(template_file).unwrap_or_else(|err| {
+        error!("Cannot load template {template_file}: {err}");
+        exit(1);
+    });
+    let conflicts = ImageHandler::template_conflicts(&template);
+    for (a, b) in &conflicts {
+        error!(
+            "{template_file}: {} ({}) and {} ({}) are too close to be told apart on screenshots",
+            Palette::slot_name(*a),
+            template.slot(*a).to_hex(),
+            Palette::slot_name(*b),
+            template.slot(*b).to_hex()
+        );
+    }
+    if !conflicts.is_empty() {
+        error!("Use create-template-pal --from {template_file} to make usable template from it");
+        exit(1);
+    }
+    template
+}
+
+/// Run colorize-image with options already merged with project configuration
+fn colorize(args: ColorizeImage) {
+    let ColorizeImage {
+        pal_file_name,
+        input_image_files,
+        template,
+        taken_with,
+        output_image_file,
+        output_dir,
+        name_template,
+        scale,
+        merge,
+        no_merge: _,
+        max_columns,
+        merge_layout,
+        pad_slot,
+        gbc_compat,
+        quantize_555,
+        generate_html,
+        no_html: _,
+        cache_dir,
+        watch,
+    } = args;
+    if pal_file_name.is_empty() || input_image_files.is_empty() {
+        error!(
+            "Palettes and screenshots are required, give them on command line or in {}",
+            ProjectConfig::FILE_NAME
+        );
+        exit(1);
+    }
+    let gallery = output_dir.as_deref().map(Gallery::new);
+    let Some(output_image_file) =
+        output_image_file.or_else(|| gallery.as_ref().map(|gallery| gallery.output_image_file()))
+    else {
+        error!(
+            "Either --output or --output-dir is required, or output / output_dir in {}",
+            ProjectConfig::FILE_NAME
+        );
+        exit(1);
+    };
+    let name_template =
+        name_template.or_else(|| gallery.as_ref().map(|_| Gallery::name_template()));
+    if let Some(last_slash) = &output_image_file.rfind('/') {
+        let output_dir = &output_image_file[0..*last_slash];
+        if !Path::new(output_dir).exists() {
+            warn!("Directory '{output_dir}' does not exists, it will be created");
+            fs::create_dir_all(output_dir).expect("Cannot create directory");
+        }
+    }
+    let color_mode = match gbc_compat.as_deref() {
+        None => ColorMode::Template,
+        Some("auto") => ColorMode::GbcCompat(None),
+        Some(name) => match GbcCompatPalette::load(name) {
+            Ok(palette) => ColorMode::GbcCompat(Some(palette)),
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        },
+    };
+    let options = ColorizeOptions {
+        output_scale: scale,
+        // --no-merge and --no-html only matter when merging with project configuration
+        merge: merge.unwrap_or(false),
+        max_columns: max_columns.unwrap_or(4),
+        merge_layout: merge_layout.unwrap_or_default(),
+        pad_slot,
+        color_mode,
+        template: match taken_with {
+            Some(pal_file) => Palette::load(&pal_file).unwrap_or_else(|err| {
+                error!("Cannot load palette {pal_file}: {err}");
+                exit(1);
+            }),
+            None => load_template(template.as_deref()),
+        },
+        quantize_555,
+        generate_html: generate_html.unwrap_or(false),
+        name_template,
+        cache: cache_dir.map(|cache_dir| Arc::new(BuildCache::new(&cache_dir))),
+    };
+    if watch {
+        Watch::new(
+            &pal_file_name,
+            &input_image_files,
+            &output_image_file,
+            &options,
+            gallery,
+        )
+        .run();
+    } else {
+        let result = match gallery {
+            Some(gallery) => gallery.colorize(&pal_file_name, &input_image_files, &options),
+            None => ImageHandler::use_palettes_to_color_images(
+                &pal_file_name,
+                &input_image_files,
+                &output_image_file,
+                &options,
+            )
+            .map(|_| ()),
+        };
+        if let Err(err) = result {
+            error!("{err}");
+            exit(1);
+        }
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
-    setup_logging(cli.log_level.into());
+    let config = ProjectConfig::discover();
+    let config_level = config.as_ref().ok().and_then(|c| c.as_ref()?.log_level);
+    setup_logging(cli.log_level.or(config_level).unwrap_or_default().into());
     info!(
         "{} [{}] loaded",
         env!("CARGO_PKG_NAME"),
         env!("GIT_HASH_SHORT")
     );
+    let config = match config {
+        Ok(config) => config,
+        Err(err) if matches!(cli.command, Commands::ColorizeImage(_) | Commands::Run(_)) => {
+            error!("{err}");
+            exit(1);
+        }
+        // Other commands only take log level from it, and init can overwrite it
+        Err(err) => {
+            warn!("{err}");
+            None
+        }
+    };
+    if let Some(config) = &config {
+        debug!("Using project configuration {:?}", config.file);
+    }
     match cli.command {
         Commands::Display(Display {
             display_type,
             pal_file_name,
+            quantize_555,
         }) => {
             let palette = Palette::load(&pal_file_name)
                 .unwrap_or_else(|err| panic!("Cannot load palette: {err}"));
+            let palette = if quantize_555 {
+                palette.lint_555(&pal_file_name);
+                palette.quantize_555()
+            } else {
+                palette
+            };
             debug!("Loaded palette:\n{:?}", &palette);
+            let metadata = PaletteMetadata::load(&pal_file_name);
+            if !metadata.is_empty() {
+                info!("Palette {pal_file_name}:\n{}", metadata.header().trim_end());
+            }
             info!(
                 "Palette as ANSI 24-bit colored strings:\n{}",
                 palette.as_ansi(display_type)
             );
         }
-        Commands::CreateTemplatePal(CreateTemplatePal { output_pal_file }) => {
-            let palette = Palette::default();
+        Commands::CreateTemplatePal(CreateTemplatePal {
+            output_pal_file,
+            style,
+            from,
+            min_distance,
+        }) => {
+            let (palette, description) = match &from {
+                Some(pal_file) => {
+                    let base = Palette::load(pal_file).unwrap_or_else(|err| {
+                        error!("Cannot load palette {pal_file}: {err}");
+                        exit(1);
+                    });
+                    let template = base.to_template(min_distance).unwrap_or_else(|err| {
+                        error!("Cannot create template from {pal_file}: {err}");
+                        exit(1);
+                    });
+                    (template, format!("Template palette based on {pal_file}"))
+                }
+                None => (
+                    Palette::template(style),
+                    format!("Template palette, {style:?} style"),
+                ),
+            };
+            info!(
+                "Template palette:\n{}",
+                palette.as_ansi(AsAnsiType::ColorValueHex)
+            );
             palette.save(&output_pal_file);
+            // Record that it is template, so screenshots taken with it are recognized
+            let mut metadata = PaletteMetadata::load(&output_pal_file);
+            if !metadata.has_tag(ScreenshotClassifier::TEMPLATE_TAG) {
+                metadata
+                    .tags
+                    .push(ScreenshotClassifier::TEMPLATE_TAG.to_string());
+            }
+            metadata.description.get_or_insert(description);
+            metadata
+                .save(&output_pal_file)
+                .unwrap_or_else(|err| warn!("Cannot save metadata of {output_pal_file}: {err}"));
+        }
+        Commands::ColorizeImage(mut args) => {
+            if let Some(config) = &config {
+                config.colorize_args(&mut args).unwrap_or_else(|err| {
+                    error!("{err}");
+                    exit(1);
+                });
+            }
+            colorize(args);
         }
-        Commands::ColorizeImage(ColorizeImage {
+        Commands::Run(Run { jobs }) => {
+            let Some(config) = &config else {
+                error!(
+                    "No {} found in current directory or its parents, create one with init",
+                    ProjectConfig::FILE_NAME
+                );
+                exit(1);
+            };
+            let jobs = if jobs.is_empty() {
+                config.jobs.keys().cloned().collect()
+            } else {
+                jobs
+            };
+            for job in jobs {
+                let args = config.job_args(&job).unwrap_or_else(|err| {
+                    error!("{err}");
+                    exit(1);
+                });
+                info!("Running job '{job}' from {:?}", config.file);
+                colorize(args);
+            }
+        }
+        Commands::Init(Init { force }) => match ProjectConfig::init(force) {
+            Ok(file) => info!("Project configuration written to {file:?}"),
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        },
+        Commands::Preview(Preview {
             pal_file_name,
             input_image_files,
-            output_image_file,
             scale,
-            merge,
-            max_columns,
-            merge_layout,
-            generate_html,
+            protocol,
+            template,
         }) => {
-            if let Some(last_slash) = &output_image_file.rfind('/') {
-                let output_dir = &output_image_file[0..*last_slash];
-                if !Path::new(output_dir).exists() {
-                    warn!("Directory '{output_dir}' does not exists, it will be created");
-                    fs::create_dir_all(output_dir).expect("Cannot create directory");
-                }
-            }
-            ImageHandler::use_palettes_to_color_images(
+            let template = load_template(template.as_deref());
+            TerminalPreview::preview(
                 &pal_file_name,
                 &input_image_files,
-                &output_image_file,
                 scale,
-                merge,
-                max_columns,
-                merge_layout,
-                generate_html,
+                protocol,
+                &template,
+            );
+        }
+        Commands::Edit(Edit {
+            pal_file_name,
+            image_file,
+            scale,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            Editor::new(&pal_file_name, image_file.as_deref(), scale, template)
+                .run()
+                .unwrap_or_else(|err| panic!("Terminal error in editor: {err}"));
+        }
+        Commands::Serve(Serve {
+            pal_file_name,
+            input_image_files,
+            port,
+            scale,
+            save_dir,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            Server::new(
+                &pal_file_name,
+                &input_image_files,
+                scale,
+                save_dir,
+                template,
+            )
+            .run(port);
+        }
+        Commands::ImportSgb(ImportSgb {
+            input_file,
+            output_pal_file,
+            system_palettes,
+            rule,
+        }) => {
+            let palette = SgbPalettes::read_data(&input_file)
+                .and_then(|data| {
+                    if system_palettes.is_empty() {
+                        SgbPalettes::from_packets(&data)
+                    } else {
+                        SgbPalettes::from_system_dump(&data, &system_palettes)
+                    }
+                })
+                .and_then(|sgb_palettes| sgb_palettes.to_palette(rule))
+                .unwrap_or_else(|err| {
+                    error!("Cannot import SGB palette from {input_file}: {err}");
+                    exit(1);
+                });
+            info!(
+                "Imported palette:\n{}",
+                palette.as_ansi(AsAnsiType::ColorValueHex)
+            );
+            palette.save(&output_pal_file);
+        }
+        Commands::Convert(Convert {
+            input_file,
+            output_file,
+            from,
+            to,
+            collapse,
+        }) => {
+            if let Err(err) = Converter::read(&input_file, from)
+                .and_then(|palette| Converter::write(&palette, &output_file, to, collapse))
+            {
+                error!("Cannot convert {input_file}: {err}");
+                exit(1);
+            }
+        }
+        Commands::ConvertFolder(ConvertFolder {
+            input_dir,
+            output_dir,
+            from,
+            to,
+            collapse,
+        }) => match Converter::convert_folder(&input_dir, &output_dir, from, to, collapse) {
+            Ok(converted) => info!("Converted {converted} palette(s) to {output_dir}"),
+            Err(err) => {
+                error!("Cannot convert {input_dir}: {err}");
+                exit(1);
+            }
+        },
+        Commands::Library(LibrarySearch {
+            query,
+            tags,
+            pal_file_names,
+        }) => {
+            let mut found = 0;
+            for pal_file in Helpers::glob_paths(&pal_file_names) {
+                let metadata = PaletteMetadata::load(&pal_file);
+                let matches = query
+                    .as_ref()
+                    .is_none_or(|query| metadata.matches(query) || pal_file.contains(query))
+                    && tags.iter().all(|tag| metadata.has_tag(tag));
+                if !matches {
+                    continue;
+                }
+                let palette = match Palette::load(&pal_file) {
+                    Ok(palette) => palette,
+                    Err(err) => {
+                        warn!("Skipping palette {pal_file}: {err}");
+                        continue;
+                    }
+                };
+                found += 1;
+                info!(
+                    "{pal_file}\n{}\n{}",
+                    palette.swatch(),
+                    metadata.header().trim_end()
+                );
+            }
+            info!("Found {found} palette(s)");
+        }
+        Commands::Pocket(Pocket { command }) => {
+            let sd_path = match &command {
+                PocketCommands::Install(PocketInstall { sd_path, .. })
+                | PocketCommands::List(PocketList { sd_path })
+                | PocketCommands::ImportScreenshots(PocketImportScreenshots { sd_path, .. })
+                | PocketCommands::Sync(PocketSync { sd_path, .. }) => sd_path.clone(),
+            };
+            let pocket = pocket::Pocket::new(&sd_path).unwrap_or_else(|err| {
+                error!("{err}");
+                exit(1);
+            });
+            let result = match command {
+                PocketCommands::Install(PocketInstall {
+                    pal_file_names,
+                    on_collision,
+                    ..
+                }) => pocket
+                    .install(&Helpers::glob_paths(&pal_file_names), on_collision)
+                    .map(|installed| info!("Installed {installed} palette(s)")),
+                PocketCommands::List(_) => {
+                    let palettes = pocket.palettes();
+                    for pal_file in &palettes {
+                        let name = Path::new(pal_file)
+                            .strip_prefix(pocket.palettes_dir())
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_else(|_| pal_file.clone());
+                        match Palette::load(pal_file) {
+                            Ok(palette) => info!("{} {name}", palette.swatch()),
+                            Err(err) => warn!("{name}: {err}"),
+                        }
+                    }
+                    info!("{} palette(s) installed", palettes.len());
+                    Ok(())
+                }
+                PocketCommands::ImportScreenshots(PocketImportScreenshots {
+                    library_dir, ..
+                }) => pocket
+                    .import_screenshots(&library_dir)
+                    .map(|imported| info!("Imported {imported} new screenshot(s)")),
+                PocketCommands::Sync(PocketSync {
+                    source_dir,
+                    delete,
+                    dry_run,
+                    ..
+                }) => pocket.sync(&source_dir, delete, dry_run),
+            };
+            if let Err(err) = result {
+                error!("{err}");
+                exit(1);
+            }
+        }
+        Commands::CheckScreenshots(CheckScreenshots {
+            input_image_files,
+            pal_file_names,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            let classifier = ScreenshotClassifier::new(&template, &pal_file_names);
+            let input_images = Helpers::glob_paths(&input_image_files);
+            let mut templates = 0;
+            for input_image in &input_images {
+                match classifier.classify_file(input_image) {
+                    Ok(ScreenshotKind::Template) => {
+                        info!("{input_image}: {}", ScreenshotKind::Template);
+                        templates += 1;
+                    }
+                    Ok(kind) => warn!("{input_image}: {kind}"),
+                    Err(err) => error!("{input_image}: cannot read image: {err}"),
+                }
+            }
+            info!(
+                "Checked {} screenshot(s), {} taken with template palette",
+                input_images.len(),
+                templates
             );
+            if templates < input_images.len() {
+                exit(1);
+            }
+        }
+        Commands::AnalyzeLayers(AnalyzeLayers {
+            input_image_files,
+            output_dir,
+            scale,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            let input_images = Helpers::glob_paths(&input_image_files);
+            let analyses =
+                LayerAnalysis::analyze_all(&template, &input_images, &output_dir, scale as usize)
+                    .unwrap_or_else(|err| {
+                        error!("{err}, nothing was written");
+                        exit(1);
+                    });
+            info!(
+                "Layers used in {} screenshot(s): {}; not used: {}",
+                analyses.len(),
+                LayerAnalysis::used_layers(&analyses, true),
+                LayerAnalysis::used_layers(&analyses, false)
+            );
+        }
+        Commands::Stats(Stats {
+            input_image_files,
+            group_by,
+            format,
+            output_file,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            let input_images = Helpers::glob_paths(&input_image_files);
+            let output_file = output_file.or(match format {
+                StatsFormat::Table => None,
+                StatsFormat::Json => Some("stats.json".to_string()),
+                StatsFormat::Html => Some("stats.html".to_string()),
+            });
+            let game_stats = stats::Stats::collect(&template, &input_images, group_by.as_deref());
+            stats::Stats::write(&game_stats, format, output_file.as_deref());
+        }
+        Commands::ContactSheet(ContactSheet {
+            pal_file_name,
+            input_image_files,
+            output_dir,
+            scale,
+            columns,
+            indexed,
+            template,
+        }) => {
+            let template = load_template(template.as_deref());
+            let pal_files = Helpers::glob_paths(&pal_file_name);
+            let input_images = Helpers::glob_paths(&input_image_files);
+            let sheet = contact_sheet::ContactSheet::new(&pal_files);
+            let written = sheet
+                .write_all(
+                    &template,
+                    &input_images,
+                    &output_dir,
+                    scale as usize,
+                    columns.map(|columns| columns as usize),
+                    indexed,
+                )
+                .unwrap_or_else(|err| {
+                    error!("{err}, nothing was written");
+                    exit(1);
+                });
+            info!("Written {} contact sheet(s)", written.len());
+        }
+        Commands::Lint(Lint { pal_file_names }) => {
+            let pal_files = Helpers::glob_paths(&pal_file_names);
+            let mut problems = 0;
+            for pal_file in &pal_files {
+                match Palette::load(pal_file) {
+                    Ok(palette) => {
+                        if palette.lint_555(pal_file) {
+                            problems += 1;
+                        }
+                    }
+                    Err(err) => {
+                        error!("{pal_file}: {err}");
+                        problems += 1;
+                    }
+                }
+            }
+            info!(
+                "Checked {} palette(s), {} with problems",
+                pal_files.len(),
+                problems
+            );
+            if problems > 0 {
+                exit(1);
+            }
+        }
+        Commands::ExportBuiltin(ExportBuiltin {
+            names,
+            all,
+            list,
+            output_dir,
+        }) => {
+            let builtins: Vec<BuiltinPalette> = if all || (list && names.is_empty()) {
+                Library::all()
+            } else {
+                names
+                    .iter()
+                    .flat_map(|name| {
+                        let name = name.strip_prefix(Library::PREFIX).unwrap_or(name);
+                        let found = Library::glob(name);
+                        if found.is_empty() {
+                            error!("There is no built-in palette '{name}'");
+                            exit(1);
+                        }
+                        found
+                    })
+                    .unique()
+                    .filter_map(|name| Library::get(&name[Library::PREFIX.len()..]))
+                    .collect()
+            };
+            if builtins.is_empty() {
+                error!("Nothing to export, provide palette names or --all");
+                exit(1);
+            }
+            if list {
+                for builtin in builtins {
+                    info!(
+                        "{}{} - {}\n{}",
+                        Library::PREFIX,
+                        builtin.name,
+                        builtin.description,
+                        builtin.palette.as_ansi(AsAnsiType::JustColor)
+                    );
+                }
+            } else {
+                Library::export(&builtins, &output_dir);
+            }
         }
     };
 }
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::library::Library;
+
+/// Optional information about palette, which .pal file itself cannot carry.
+///
+/// Read from sidecar `foo.pal.toml` next to `foo.pal`, or from [`Manifest`] of palette folder.
+/// Sidecar values take precedence over manifest ones
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PaletteMetadata {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub license: Option<String>,
+    /// Attribution text which should be kept when sharing palette
+    pub credit: Option<String>,
+    pub url: Option<String>,
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// Metadata for all palettes in folder, stored as `palettes.toml`:
+///
+/// ```toml
+/// [defaults]
+/// author = "Someone"
+///
+/// [palettes."foo.pal"]
+/// name = "Foo"
+/// tags = ["green", "warm"]
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Used for every palette in folder
+    #[serde(default)]
+    pub defaults: PaletteMetadata,
+    /// By .pal file name
+    #[serde(default)]
+    pub palettes: HashMap<String, PaletteMetadata>,
+}
+
+/// Optional information about screenshot, read from sidecar `foo.png.toml` next to `foo.png`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScreenshotMetadata {
+    /// Game screenshot was taken in, used for grouping statistics
+    pub game: Option<String>,
+}
+
+impl ScreenshotMetadata {
+    pub fn load(image_file: &str) -> Self {
+        let sidecar = format!("{}.{}", image_file, PaletteMetadata::SIDECAR_EXTENSION);
+        PaletteMetadata::read_toml(Path::new(&sidecar)).unwrap_or_default()
+    }
+}
+
+impl Manifest {
+    pub const FILE_NAME: &'static str = "palettes.toml";
+}
+
+impl PaletteMetadata {
+    pub const SIDECAR_EXTENSION: &'static str = "toml";
+
+    /// Metadata for palette file, empty if there is none.
+    ///
+    /// Built-in palettes have metadata built in as well
+    pub fn load(pal_file: &str) -> Self {
+        if let Some(name) = pal_file.strip_prefix(Library::PREFIX) {
+            return Library::get(name)
+                .map(|builtin| builtin.metadata())
+                .unwrap_or_default();
+        }
+        let path = Path::new(pal_file);
+        let mut metadata = Self::default();
+        let manifest_file = path
+            .parent()
+            .map(|parent| parent.join(Manifest::FILE_NAME))
+            .unwrap_or_else(|| Manifest::FILE_NAME.into());
+        if let Some(manifest) = Self::read_toml::<Manifest>(&manifest_file) {
+            metadata = metadata.or(manifest.defaults);
+            if let Some(palette) = path
+                .file_name()
+                .and_then(|file_name| manifest.palettes.get(file_name.to_string_lossy().as_ref()))
+            {
+                metadata = palette.clone().or(metadata);
+            }
+        }
+        let sidecar = format!("{}.{}", pal_file, Self::SIDECAR_EXTENSION);
+        if let Some(sidecar) = Self::read_toml::<Self>(Path::new(&sidecar)) {
+            metadata = sidecar.or(metadata);
+        }
+        metadata
+    }
+
+    /// Sidecar of palette file, `foo.pal.toml` for `foo.pal`
+    pub fn sidecar_file(pal_file: &Path) -> PathBuf {
+        let mut sidecar = pal_file.as_os_str().to_owned();
+        sidecar.push(format!(".{}", Self::SIDECAR_EXTENSION));
+        PathBuf::from(sidecar)
+    }
+
+    /// Sidecar contents with all metadata of palette, so it can be written next to its copy.
+    ///
+    /// Values from manifest are included, as manifest is not copied with palette.
+    /// `None` if palette has no metadata
+    pub fn sidecar_text(pal_file: &str) -> Option<String> {
+        let metadata = Self::load(pal_file);
+        (!metadata.is_empty())
+            .then(|| toml::to_string(&metadata).expect("Cannot serialize metadata"))
+    }
+
+    /// Write as sidecar of palette file, overwriting existing one
+    pub fn save(&self, pal_file: &str) -> Result<(), std::io::Error> {
+        let sidecar = format!("{}.{}", pal_file, Self::SIDECAR_EXTENSION);
+        debug!("Writing metadata to {}", sidecar);
+        let text = toml::to_string(self).expect("Cannot serialize metadata");
+        fs::write(sidecar, text)
+    }
+
+    fn read_toml<T: for<'de> Deserialize<'de>>(file: &Path) -> Option<T> {
+        let text = fs::read_to_string(file).ok()?;
+        debug!("Reading metadata from {:?}", file);
+        toml::from_str(&text)
+            .map_err(|err| warn!("Ignoring invalid metadata file {:?}: {}", file, err))
+            .ok()
+    }
+
+    /// Values from self, missing ones taken from other
+    fn or(self, other: Self) -> Self {
+        Self {
+            name: self.name.or(other.name),
+            author: self.author.or(other.author),
+            license: self.license.or(other.license),
+            credit: self.credit.or(other.credit),
+            url: self.url.or(other.url),
+            description: self.description.or(other.description),
+            tags: if self.tags.is_empty() {
+                other.tags
+            } else {
+                self.tags
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    /// Case insensitive search in all text values
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [
+            &self.name,
+            &self.author,
+            &self.license,
+            &self.credit,
+            &self.description,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&self.tags)
+        .any(|value| value.to_lowercase().contains(&query))
+    }
+
+    /// Human readable lines, only with values which are set
+    pub fn header(&self) -> String {
+        let tags = (!self.tags.is_empty()).then(|| self.tags.join(", "));
+        [
+            ("Name", &self.name),
+            ("Author", &self.author),
+            ("License", &self.license),
+            ("Credit", &self.credit),
+            ("URL", &self.url),
+            ("Description", &self.description),
+            ("Tags", &tags),
+        ]
+        .into_iter()
+        .filter_map(|(label, value)| value.as_ref().map(|value| format!("{label}: {value}\n")))
+        .collect()
+    }
+}
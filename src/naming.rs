@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("Unknown placeholder {{{0}}} in name template, use one of: {1}")]
+    UnknownPlaceholder(String, String),
+    #[error("Placeholder starting at position {0} of name template is not closed with '}}'")]
+    Unclosed(usize),
+    #[error("Output file {0} would be written for both {1} and {2}")]
+    Collision(String, String, String),
+}
+
+/// Value which can be used in [`NameTemplate`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Placeholder {
+    /// Directory of --output, or '.' if there is none
+    OutDir,
+    /// File name of --output, without .png
+    OutStem,
+    /// Palette file name without extension, or name of built-in palette
+    PaletteStem,
+    /// Name of palette from its metadata, palette stem if there is none
+    PaletteName,
+    /// Directory of palette file, 'builtin' for built-in palettes
+    PaletteDir,
+    /// Screenshot file name without extension, 'merged' when merging
+    ImageStem,
+    /// Number of screenshot, as 000, 001...
+    Counter,
+    /// Scale factor of output image
+    Scale,
+}
+
+impl Placeholder {
+    pub const ALL: [Placeholder; 8] = [
+        Placeholder::OutDir,
+        Placeholder::OutStem,
+        Placeholder::PaletteStem,
+        Placeholder::PaletteName,
+        Placeholder::PaletteDir,
+        Placeholder::ImageStem,
+        Placeholder::Counter,
+        Placeholder::Scale,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placeholder::OutDir => "out_dir",
+            Placeholder::OutStem => "out_stem",
+            Placeholder::PaletteStem => "palette_stem",
+            Placeholder::PaletteName => "palette_name",
+            Placeholder::PaletteDir => "palette_dir",
+            Placeholder::ImageStem => "image_stem",
+            Placeholder::Counter => "counter",
+            Placeholder::Scale => "scale",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// What one output file is made of, used to fill placeholders of [`NameTemplate`]
+#[derive(Debug, Clone)]
+pub struct NameContext<'a> {
+    /// Value of --output
+    pub output_image_file: &'a str,
+    pub pal_file: &'a str,
+    /// Screenshot, `None` when merging
+    pub image_file: Option<&'a str>,
+    pub counter: usize,
+    pub scale: u8,
+}
+
+/// Output file name with placeholders, e.g. `{out_dir}/{palette_stem}/{image_stem}_{scale}x.png`.
+///
+/// Values of placeholders are sanitized, so they are safe file (or directory) names,
+/// only '/' in template itself and in `{out_dir}` / `{palette_dir}` create directories
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = Error;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or(Error::Unclosed(template.len() - rest.len() + start))?;
+            let name = &rest[start + 1..start + end];
+            let placeholder = Placeholder::from_name(name).ok_or_else(|| {
+                let names: Vec<String> = Placeholder::ALL
+                    .iter()
+                    .map(|p| format!("{{{}}}", p.name()))
+                    .collect();
+                Error::UnknownPlaceholder(name.to_string(), names.join(", "))
+            })?;
+            parts.push(Part::Placeholder(placeholder));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => write!(f, "{text}")?,
+                Part::Placeholder(placeholder) => write!(f, "{{{}}}", placeholder.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NameTemplate {
+    /// Characters which are not allowed in file names on some of systems
+    const UNSAFE_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+    /// Safe file name: unsafe and control characters are replaced by '_',
+    /// as are leading and trailing dots and spaces, so '..' cannot be used to escape directory
+    pub fn sanitize(name: &str) -> String {
+        let mut chars: Vec<char> = name
+            .chars()
+            .map(
+                |c| match Self::UNSAFE_CHARS.contains(&c) || c.is_control() {
+                    true => '_',
+                    false => c,
+                },
+            )
+            .collect();
+        let padding = |c: &char| *c == '.' || *c == ' ';
+        let leading = chars.iter().take_while(|c| padding(c)).count();
+        let trailing = chars.iter().rev().take_while(|c| padding(c)).count();
+        let len = chars.len();
+        chars[..leading].fill('_');
+        chars[len - trailing..].fill('_');
+        match chars.is_empty() {
+            true => "_".to_string(),
+            false => chars.into_iter().collect(),
+        }
+    }
+
+    /// Relative directory with each component sanitized, root and '..' components are dropped
+    pub(crate) fn sanitize_dir(dir: &Path) -> String {
+        let components: Vec<String> = dir
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(Self::sanitize(&name.to_string_lossy())),
+                _ => None,
+            })
+            .collect();
+        match components.is_empty() {
+            true => ".".to_string(),
+            false => components.join("/"),
+        }
+    }
+
+    fn stem(file: &str) -> String {
+        Path::new(file)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn value(placeholder: Placeholder, context: &NameContext) -> String {
+        let builtin = context.pal_file.strip_prefix(Library::PREFIX);
+        match placeholder {
+            Placeholder::OutDir => match Path::new(context.output_image_file).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().to_string(),
+                _ => ".".to_string(),
+            },
+            Placeholder::OutStem => Self::sanitize(&Self::stem(context.output_image_file)),
+            Placeholder::PaletteStem => {
+                Self::sanitize(&builtin.map_or_else(|| Self::stem(context.pal_file), String::from))
+            }
+            Placeholder::PaletteName => match PaletteMetadata::load(context.pal_file).name {
+                Some(name) => Self::sanitize(&name),
+                None => Self::value(Placeholder::PaletteStem, context),
+            },
+            Placeholder::PaletteDir => match builtin {
+                Some(_) => "builtin".to_string(),
+                None => Path::new(context.pal_file)
+                    .parent()
+                    .map(Self::sanitize_dir)
+                    .unwrap_or_else(|| ".".to_string()),
+            },
+            Placeholder::ImageStem => context
+                .image_file
+                .map(|image_file| Self::sanitize(&Self::stem(image_file)))
+                .unwrap_or_else(|| "merged".to_string()),
+            Placeholder::Counter => format!("{:03}", context.counter),
+            Placeholder::Scale => context.scale.to_string(),
+        }
+    }
+
+    /// Whether placeholder is used anywhere in template
+    pub fn uses(&self, placeholder: Placeholder) -> bool {
+        self.parts.contains(&Part::Placeholder(placeholder))
+    }
+
+    /// Output file name for given context, '.png' is added if template does not end with it.
+    ///
+    /// Only template itself is checked, so placeholder value ending with '.png'
+    /// (e.g. screenshot `test.png.png`) still gets its own extension
+    pub fn render(&self, context: &NameContext) -> String {
+        let has_extension = matches!(
+            self.parts.last(),
+            Some(Part::Text(text)) if text.to_lowercase().ends_with(".png")
+        );
+        let name: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Placeholder(placeholder) => Self::value(*placeholder, context),
+            })
+            .collect();
+        if has_extension {
+            name
+        } else {
+            format!("{name}.png")
+        }
+    }
+
+    /// Error for first output file which would be written more than once,
+    /// `outputs` are pairs of output file and description of what it is made of
+    pub fn check_collisions<'a>(
+        outputs: impl IntoIterator<Item = (&'a str, String)>,
+    ) -> Result<(), Error> {
+        let mut seen: HashMap<&str, String> = HashMap::new();
+        for (output_file, source) in outputs {
+            if let Some(first) = seen.get(output_file) {
+                return Err(Error::Collision(
+                    output_file.to_string(),
+                    first.clone(),
+                    source,
+                ));
+            }
+            seen.insert(output_file, source);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(image_file: &str) -> NameContext<'_> {
+        NameContext {
+            output_image_file: "out/output.png",
+            pal_file: "palettes/gb/test.pal",
+            image_file: Some(image_file),
+            counter: 1,
+            scale: 2,
+        }
+    }
+
+    fn render(template: &str, image_file: &str) -> String {
+        template
+            .parse::<NameTemplate>()
+            .unwrap()
+            .render(&context(image_file))
+    }
+
+    #[test]
+    fn unclosed_placeholder() {
+        assert_eq!(
+            "a_{palette_stem".parse::<NameTemplate>(),
+            Err(Error::Unclosed(2))
+        );
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        let err = "{out_dir}/{palette}.png"
+            .parse::<NameTemplate>()
+            .unwrap_err();
+        assert!(matches!(err, Error::UnknownPlaceholder(name, _) if name == "palette"));
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(
+            render(
+                "{out_dir}/{palette_dir}/{palette_stem}/{image_stem}_{counter}_{scale}x",
+                "shots/tetris.png"
+            ),
+            "out/palettes/gb/test/tetris_001_2x.png"
+        );
+    }
+
+    #[test]
+    fn sanitizes_dots() {
+        assert_eq!(NameTemplate::sanitize(".."), "__");
+        assert_eq!(NameTemplate::sanitize(".hidden"), "_hidden");
+        assert_eq!(NameTemplate::sanitize("a/../b"), "a_.._b");
+        assert_eq!(NameTemplate::sanitize("name. "), "name__");
+        assert_eq!(NameTemplate::sanitize(""), "_");
+        assert_eq!(
+            NameTemplate::sanitize_dir(Path::new("/palettes/../gb")),
+            "palettes/gb"
+        );
+        assert_eq!(render("{image_stem}", "shots/...png"), "__.png");
+        assert_eq!(render("{image_stem}", "shots/.hidden.png"), "_hidden.png");
+    }
+
+    #[test]
+    fn png_suffix_from_template_only() {
+        assert_eq!(render("{image_stem}", "test.png.png"), "test.png.png");
+        assert_eq!(render("{image_stem}.png", "test.png.png"), "test.png.png");
+        assert_eq!(render("{image_stem}.PNG", "test.png"), "test.PNG");
+        assert_eq!(render("{image_stem}_x", "test.png"), "test_x.png");
+    }
+
+    #[test]
+    fn collision() {
+        let outputs = [
+            ("out/a.png", "a.png".to_string()),
+            ("out/b.png", "b.png".to_string()),
+            ("out/a.png", "other/a.png".to_string()),
+        ];
+        assert_eq!(
+            NameTemplate::check_collisions(outputs.clone()),
+            Err(Error::Collision(
+                "out/a.png".to_string(),
+                "a.png".to_string(),
+                "other/a.png".to_string()
+            ))
+        );
+        assert_eq!(
+            NameTemplate::check_collisions(outputs[..2].to_vec()),
+            Ok(())
+        );
+    }
+}
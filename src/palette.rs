@@ -1,6 +1,7 @@
 use clap::ValueEnum;
 use colored::*;
-use log::{debug, error};
+use itertools::Itertools;
+use log::{debug, warn};
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::{fs, io};
@@ -9,6 +10,8 @@ use std::fs::File;
 use std::io::Write;
 use thiserror::Error;
 
+use crate::library::Library;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Cannot convert from Palette to Vec<u8>")]
@@ -19,7 +22,19 @@ pub enum Error {
     IncorrectFooter,
     #[error("Error while reading file {0}")]
     IoError(#[from] io::Error),
+    #[error("There is no built-in palette '{0}', see export-builtin --list")]
+    UnknownBuiltin(String),
+    #[error("Cannot fit {} colors at least {0} apart", Palette::SLOTS)]
+    TooDistant(u8),
+}
+
+/// Array of colors from 0xRRGGBB literals
+macro_rules! hex_colors {
+    ($($hex: literal),*) => {
+        [$([(($hex as u32) >> 16) as u8, (($hex as u32) >> 8) as u8, ($hex as u32) as u8]),*]
+    };
 }
+pub(crate) use hex_colors;
 
 pub type Color = [u8; 3];
 pub type Colors = [Color; 4];
@@ -92,6 +107,16 @@ impl Default for Palette {
     }
 }
 
+/// Built-in way of generating template palette
+#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+pub enum TemplateStyle {
+    /// Same template as always, shades of black, green, blue and red
+    #[default]
+    Default,
+    /// Colors as far apart as possible, for screenshots which went through lossy capture
+    Contrast,
+}
+
 #[derive(Debug, Copy, Clone, Default, ValueEnum)]
 pub enum AsAnsiType {
     JustColor,
@@ -111,6 +136,12 @@ pub trait AsAnsi {
 
 pub trait ColorExt {
     fn contrast_color(&self) -> Color;
+    fn to_hex(&self) -> String;
+    fn from_hex(hex: &str) -> Option<Color>;
+    fn to_hsl(&self) -> [f32; 3];
+    fn from_hsl(hsl: [f32; 3]) -> Color;
+    fn to_bgr555(&self) -> u16;
+    fn from_bgr555(value: u16) -> Color;
 }
 
 impl ColorExt for Color {
@@ -124,6 +155,99 @@ impl ColorExt for Color {
         let value = if luminance > 0.5 { 0 } else { 255 };
         [value, value, value]
     }
+
+    /// Format as `#rrggbb`
+    fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self[0], self[1], self[2])
+    }
+
+    /// Parse `#rrggbb`, `rrggbb` or short `#rgb` notation
+    fn from_hex(hex: &str) -> Option<Color> {
+        let hex = hex.trim().trim_start_matches('#');
+        let hex = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect(),
+            6 => hex.to_string(),
+            _ => return None,
+        };
+        let value = u32::from_str_radix(&hex, 16).ok()?;
+        Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+    }
+
+    /// Convert to hue (0..360), saturation (0..1) and lightness (0..1)
+    fn to_hsl(&self) -> [f32; 3] {
+        let [r, g, b] = self.map(|c| c as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let lightness = (max + min) / 2.0;
+        if max == min {
+            return [0.0, 0.0, lightness];
+        }
+        let delta = max - min;
+        let saturation = if lightness > 0.5 {
+            delta / (2.0 - max - min)
+        } else {
+            delta / (max + min)
+        };
+        let hue = if max == r {
+            (g - b) / delta + if g < b { 6.0 } else { 0.0 }
+        } else if max == g {
+            (b - r) / delta + 2.0
+        } else {
+            (r - g) / delta + 4.0
+        };
+        [hue * 60.0, saturation, lightness]
+    }
+
+    fn from_hsl(hsl: [f32; 3]) -> Color {
+        let [hue, saturation, lightness] = hsl;
+        let hue = hue.rem_euclid(360.0) / 360.0;
+        let saturation = saturation.clamp(0.0, 1.0);
+        let lightness = lightness.clamp(0.0, 1.0);
+        if saturation == 0.0 {
+            let value = (lightness * 255.0).round() as u8;
+            return [value, value, value];
+        }
+        let q = if lightness < 0.5 {
+            lightness * (1.0 + saturation)
+        } else {
+            lightness + saturation - lightness * saturation
+        };
+        let p = 2.0 * lightness - q;
+        let channel = |t: f32| {
+            let t = t.rem_euclid(1.0);
+            let value = if t < 1.0 / 6.0 {
+                p + (q - p) * 6.0 * t
+            } else if t < 0.5 {
+                q
+            } else if t < 2.0 / 3.0 {
+                p + (q - p) * (2.0 / 3.0 - t) * 6.0
+            } else {
+                p
+            };
+            (value * 255.0).round() as u8
+        };
+        [
+            channel(hue + 1.0 / 3.0),
+            channel(hue),
+            channel(hue - 1.0 / 3.0),
+        ]
+    }
+
+    /// 15-bit color used by GBC and SGB hardware: 5 bits per channel, red in lowest bits
+    fn to_bgr555(&self) -> u16 {
+        let [r, g, b] = self.map(|channel| channel as u16 >> 3);
+        b << 10 | g << 5 | r
+    }
+
+    /// 5-bit channels are expanded to 8 bits by repeating highest bits,
+    /// so 0x1F becomes 0xFF and 0 stays 0
+    fn from_bgr555(value: u16) -> Color {
+        let channel = |shift: u16| {
+            let channel = ((value >> shift) & 0x1F) as u8;
+            channel << 3 | channel >> 2
+        };
+        [channel(0), channel(5), channel(10)]
+    }
 }
 
 impl AsAnsi for Color {
@@ -176,8 +300,224 @@ impl AsAnsiVec for Colors {
 }
 
 impl Palette {
-    /// Load palette from file
+    /// Number of colors in palette: 4 layers with 4 colors each and lcd_off.
+    ///
+    /// Slots are numbered in the same order as they are stored in .pal file,
+    /// which is also the order of indexes in paletted .png files we generate
+    pub const SLOTS: usize = 17;
+
+    pub fn new(bg: Colors, obj0: Colors, obj1: Colors, window: Colors, lcd_off: Color) -> Self {
+        Self {
+            bg,
+            obj0,
+            obj1,
+            window,
+            lcd_off,
+        }
+    }
+
+    /// The same 4 shades on every layer, like on original Game Boy
+    pub fn from_shades(shades: Colors, lcd_off: Color) -> Self {
+        Self::new(shades, shades, shades, shades, lcd_off)
+    }
+
+    /// Get color from slot, see [`Palette::SLOTS`] for numbering
+    pub fn slot(&self, index: usize) -> Color {
+        match index {
+            0..=3 => self.bg[index],
+            4..=7 => self.obj0[index - 4],
+            8..=11 => self.obj1[index - 8],
+            12..=15 => self.window[index - 12],
+            16 => self.lcd_off,
+            _ => panic!("Palette slot {index} does not exist"),
+        }
+    }
+
+    /// Set color of slot, see [`Palette::SLOTS`] for numbering
+    pub fn set_slot(&mut self, index: usize, color: Color) {
+        match index {
+            0..=3 => self.bg[index] = color,
+            4..=7 => self.obj0[index - 4] = color,
+            8..=11 => self.obj1[index - 8] = color,
+            12..=15 => self.window[index - 12] = color,
+            16 => self.lcd_off = color,
+            _ => panic!("Palette slot {index} does not exist"),
+        }
+    }
+
+    /// Name of slot, same as used as keys in `HashMap<String, Color>` conversion
+    pub fn slot_name(index: usize) -> String {
+        match index {
+            0..=3 => format!("bg_{}", index),
+            4..=7 => format!("obj0_{}", index - 4),
+            8..=11 => format!("obj1_{}", index - 8),
+            12..=15 => format!("window_{}", index - 12),
+            16 => "lcd_off".to_string(),
+            _ => panic!("Palette slot {index} does not exist"),
+        }
+    }
+
+    /// Palette as it would look on hardware with 15-bit colors, like GBC or SGB
+    pub fn quantize_555(&self) -> Self {
+        let mut palette = self.clone();
+        for slot in 0..Self::SLOTS {
+            palette.set_slot(slot, Color::from_bgr555(self.slot(slot).to_bgr555()));
+        }
+        palette
+    }
+
+    /// All slots as 15-bit BGR555 colors
+    pub fn to_bgr555(&self) -> [u16; Self::SLOTS] {
+        std::array::from_fn(|slot| self.slot(slot).to_bgr555())
+    }
+
+    pub fn from_bgr555(colors: [u16; Self::SLOTS]) -> Self {
+        let mut palette = Self::default();
+        for (slot, color) in colors.into_iter().enumerate() {
+            palette.set_slot(slot, Color::from_bgr555(color));
+        }
+        palette
+    }
+
+    /// Pairs of slots with different colors, which become the same color when quantized to BGR555
+    pub fn collisions_555(&self) -> Vec<(usize, usize)> {
+        let bgr555 = self.to_bgr555();
+        (0..Self::SLOTS)
+            .tuple_combinations()
+            .filter(|(a, b)| self.slot(*a) != self.slot(*b) && bgr555[*a] == bgr555[*b])
+            .collect()
+    }
+
+    /// Log warning for each pair of slots which collapse to the same BGR555 color,
+    /// returns true if there were any
+    pub fn lint_555(&self, name: &str) -> bool {
+        let collisions = self.collisions_555();
+        for (a, b) in &collisions {
+            warn!(
+                "{}: {} ({}) and {} ({}) are the same color in 15-bit BGR555: {}",
+                name,
+                Self::slot_name(*a),
+                self.slot(*a).to_hex(),
+                Self::slot_name(*b),
+                self.slot(*b).to_hex(),
+                Color::from_bgr555(self.to_bgr555()[*a]).to_hex()
+            );
+        }
+        !collisions.is_empty()
+    }
+
+    /// Biggest difference of any channel between two colors
+    pub fn distance(a: Color, b: Color) -> u8 {
+        a.iter().zip(b).map(|(a, b)| a.abs_diff(b)).max().unwrap()
+    }
+
+    /// Pairs of slots closer than `min_distance` (see [`Self::distance`]), so they could be confused on screenshots
+    pub fn template_conflicts(&self, min_distance: u8) -> Vec<(usize, usize)> {
+        (0..Self::SLOTS)
+            .tuple_combinations()
+            .filter(|(a, b)| Self::distance(self.slot(*a), self.slot(*b)) < min_distance)
+            .collect()
+    }
+
+    /// Groups of slots sharing the same color, only groups of 2 or more slots are returned
+    pub fn shared_slots(&self) -> Vec<Vec<usize>> {
+        (0..Self::SLOTS)
+            .into_group_map_by(|slot| self.slot(*slot))
+            .into_values()
+            .filter(|slots| slots.len() > 1)
+            .sorted()
+            .collect()
+    }
+
+    /// Generate template palette of given style
+    pub fn template(style: TemplateStyle) -> Self {
+        match style {
+            TemplateStyle::Default => Self::default(),
+            // Every channel is 0x00, 0x55, 0xaa or 0xff, so colors are at least 0x55 apart
+            TemplateStyle::Contrast => Self::new(
+                hex_colors!(0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000),
+                hex_colors!(0xFFAAAA, 0xFF5555, 0xAA0000, 0x550000),
+                hex_colors!(0xAAFFAA, 0x55FF55, 0x00AA00, 0x005500),
+                hex_colors!(0xAAAAFF, 0x5555FF, 0x0000AA, 0x000055),
+                [0xFF, 0x00, 0xFF],
+            ),
+        }
+    }
+
+    /// Template which looks like this palette, so it is bearable to play with,
+    /// but with colors moved apart until all slots are at least `min_distance` from each other
+    pub fn to_template(&self, min_distance: u8) -> Result<Self, Error> {
+        // Lighter / darker first, so colors keep their hue if possible
+        let directions: [[i16; 3]; 8] = [
+            [1, 1, 1],
+            [-1, -1, -1],
+            [1, 0, 0],
+            [-1, 0, 0],
+            [0, 1, 0],
+            [0, -1, 0],
+            [0, 0, 1],
+            [0, 0, -1],
+        ];
+        let mut result = self.clone();
+        let mut taken: Vec<Color> = Vec::with_capacity(Self::SLOTS);
+        for slot in 0..Self::SLOTS {
+            let free = |color: &Color| {
+                taken
+                    .iter()
+                    .all(|other| Self::distance(*color, *other) >= min_distance)
+            };
+            let original = self.slot(slot);
+            let moved = (0..=255_i16)
+                .flat_map(|step| directions.iter().map(move |direction| (step, direction)))
+                .map(|(step, direction)| {
+                    let mut color = original;
+                    for (channel, delta) in color.iter_mut().zip(direction) {
+                        *channel = (*channel as i16 + delta * step).clamp(0, 255) as u8;
+                    }
+                    color
+                })
+                .find(free)
+                // Nothing found near original color, any free color of coarse grid will do
+                .or_else(|| {
+                    let step = (min_distance as usize).max(1);
+                    (0..=255)
+                        .step_by(step)
+                        .cartesian_product((0..=255).step_by(step))
+                        .cartesian_product((0..=255).step_by(step))
+                        .map(|((r, g), b)| [r as u8, g as u8, b as u8])
+                        .find(free)
+                })
+                .ok_or(Error::TooDistant(min_distance))?;
+            if moved != original {
+                debug!(
+                    "Moved {} from {} to {}",
+                    Self::slot_name(slot),
+                    original.to_hex(),
+                    moved.to_hex()
+                );
+            }
+            result.set_slot(slot, moved);
+            taken.push(moved);
+        }
+        Ok(result)
+    }
+
+    /// Slot index from its name (see [`Self::slot_name`]) or number
+    pub fn slot_index(name: &str) -> Option<usize> {
+        match name.parse::<usize>() {
+            Ok(index) => (index < Self::SLOTS).then_some(index),
+            Err(_) => (0..Self::SLOTS).find(|index| Self::slot_name(*index) == name),
+        }
+    }
+
+    /// Load palette from file, or built-in palette if name starts with `builtin:`
     pub fn load(file_name: &str) -> Result<Self, Error> {
+        if let Some(name) = file_name.strip_prefix(Library::PREFIX) {
+            debug!("Loading built-in palette {}", name);
+            return Library::get(name)
+                .map(|builtin| builtin.palette)
+                .ok_or_else(|| Error::UnknownBuiltin(name.to_string()));
+        }
         debug!("Loading palette from {}", file_name);
         let data = fs::read(file_name)?;
         data.try_into()
@@ -292,6 +632,20 @@ impl AsAnsiVec for Palette {
     }
 }
 
+impl Palette {
+    /// All 17 colors in one line, layers separated by space
+    pub fn swatch(&self) -> ColoredStringVec {
+        let mut vec = ColoredStringVec(Vec::with_capacity(21));
+        for layer in [&self.bg, &self.obj0, &self.obj1, &self.window] {
+            vec.0.extend(layer.as_ansi(AsAnsiType::JustColor).0);
+            vec.0.push(" ".normal());
+        }
+        vec.0
+            .push(self.lcd_off.as_ansi(AsAnsiType::JustColor, None));
+        vec
+    }
+}
+
 pub struct ColoredStringVec(pub(self) Vec<ColoredString>);
 
 impl Display for ColoredStringVec {
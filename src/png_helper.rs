@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 
 use crate::palette::{Color, Palette};
 use itertools::Itertools;
@@ -11,6 +11,12 @@ use thiserror::Error;
 pub enum Error {
     #[error("Array referenced is too big")]
     ArrayTooBig,
+    #[error("Data is not valid .png file")]
+    NotPng,
+    #[error(".png file does not contain PLTE chunk, is it paletted image?")]
+    NoPalette,
+    #[error("Buffer of {0} bytes is too small for {1}x{2} image")]
+    BufferTooSmall(usize, usize, usize),
 }
 
 pub struct PngPalette {
@@ -114,12 +120,70 @@ impl PngPalette {
     }
 }
 
+/// Precomputed palette index for every possible RGB color, so palettizing is one table read per pixel.
+///
+/// Gives the same results as [`PngPalette::index_of_with_tolerance`], with not matched colors mapped to 255
+pub struct ColorLookup {
+    table: Vec<u8>,
+}
+
+impl ColorLookup {
+    pub const NOT_FOUND: u8 = 255;
+
+    pub fn new(palette: &PngPalette, tolerance: u8) -> Self {
+        let mut table = vec![Self::NOT_FOUND; 256 * 256 * 256];
+        let range = |value: u8| value.saturating_sub(tolerance)..=value.saturating_add(tolerance);
+        // Exact colors go first, so entries closer than tolerance to each other still match exactly.
+        // Then palette entries are filled in order and never overwritten, so first matching entry wins
+        // like in linear scan. Entry 255 is last one, so it does not matter it is the same as NOT_FOUND
+        for (index, color) in palette.pal.chunks_exact(3).enumerate() {
+            let position = Self::position([color[0], color[1], color[2]]);
+            if table[position] == Self::NOT_FOUND {
+                table[position] = index as u8;
+            }
+        }
+        for (index, color) in palette.pal.chunks_exact(3).enumerate() {
+            for r in range(color[0]) {
+                for g in range(color[1]) {
+                    for b in range(color[2]) {
+                        let position = Self::position([r, g, b]);
+                        if table[position] == Self::NOT_FOUND {
+                            table[position] = index as u8;
+                        }
+                    }
+                }
+            }
+        }
+        Self { table }
+    }
+
+    #[inline]
+    fn position(color: Color) -> usize {
+        (color[0] as usize) << 16 | (color[1] as usize) << 8 | color[2] as usize
+    }
+
+    #[inline]
+    pub fn index_of(&self, color: Color) -> u8 {
+        self.table[Self::position(color)]
+    }
+}
+
 pub struct PngHelper;
 
 impl PngHelper {
     pub fn save(file_name: &str, width: u32, height: u32, palette: &[u8], data: &[u8]) {
         let file = File::create(file_name).expect("Cannot create .png file");
-        let writer = BufWriter::new(file);
+        Self::write(BufWriter::new(file), width, height, palette, data);
+    }
+
+    /// Encode paletted image as .png file bytes kept in memory
+    pub fn encode(width: u32, height: u32, palette: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        Self::write(&mut bytes, width, height, palette, data);
+        bytes
+    }
+
+    fn write<W: Write>(writer: W, width: u32, height: u32, palette: &[u8], data: &[u8]) {
         let mut encoder = png::Encoder::new(writer, width, height);
         encoder.set_color(png::ColorType::Indexed);
         encoder.set_depth(png::BitDepth::Eight);
@@ -139,39 +203,132 @@ impl PngHelper {
         writer.write_image_data(data).unwrap(); // save
     }
 
-    pub fn get_size(file_name: &str) -> (u32, u32) {
-        let decoder = png::Decoder::new(File::open(file_name).unwrap());
-        let mut reader = decoder.read_info().unwrap();
-        let mut buf = vec![0; reader.output_buffer_size()];
-        let info = reader.next_frame(&mut buf).unwrap();
-        (info.width, info.height)
+    /// Replace palette of already encoded paletted .png file.
+    ///
+    /// Only PLTE chunk is rewritten, so compressed image data is copied as is,
+    /// which is much cheaper than encoding whole image again.
+    pub fn replace_palette(png_bytes: &[u8], palette: &[u8]) -> Result<Vec<u8>, Error> {
+        const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        if png_bytes.len() < SIGNATURE.len() || png_bytes[0..8] != SIGNATURE {
+            return Err(Error::NotPng);
+        }
+        let mut output = Vec::with_capacity(png_bytes.len() + palette.len());
+        output.extend_from_slice(&SIGNATURE);
+        let mut position = SIGNATURE.len();
+        let mut replaced = false;
+        // Each chunk: 4 bytes length, 4 bytes type, data, 4 bytes CRC of type + data
+        while position + 8 <= png_bytes.len() {
+            let length =
+                u32::from_be_bytes(png_bytes[position..position + 4].try_into().unwrap()) as usize;
+            let chunk_end = position + 12 + length;
+            if chunk_end > png_bytes.len() {
+                return Err(Error::NotPng);
+            }
+            let chunk_type = &png_bytes[position + 4..position + 8];
+            if chunk_type == b"PLTE" {
+                let mut hasher = crc32fast::Hasher::new();
+                hasher.update(chunk_type);
+                hasher.update(palette);
+                output.extend_from_slice(&(palette.len() as u32).to_be_bytes());
+                output.extend_from_slice(chunk_type);
+                output.extend_from_slice(palette);
+                output.extend_from_slice(&hasher.finalize().to_be_bytes());
+                replaced = true;
+            } else {
+                output.extend_from_slice(&png_bytes[position..chunk_end]);
+            }
+            position = chunk_end;
+        }
+        if replaced {
+            Ok(output)
+        } else {
+            Err(Error::NoPalette)
+        }
+    }
+
+    /// Get image size, only header of the file is read
+    pub fn get_size(file_name: &str) -> Result<(u32, u32), png::DecodingError> {
+        let decoder = png::Decoder::new(File::open(file_name)?);
+        let reader = decoder.read_info()?;
+        let info = reader.info();
+        Ok((info.width, info.height))
     }
 
-    /// Copy image bytes from one source to destination.
+    /// Copy image bytes from source to destination at given position.
     ///
-    /// Does NOT check if source image fits in destination and does not crops,
-    /// so be aware of panics
+    /// Parts of source image which do not fit in destination are cropped.
+    /// Returns error if buffers are smaller than their declared sizes
+    #[allow(clippy::too_many_arguments)]
     pub fn copy_from_to(
         source: &[u8],
         source_width: usize,
         source_height: usize,
         destination: &mut [u8],
         destination_width: usize,
-        _destination_height: usize,
+        destination_height: usize,
         destination_x: usize,
         destination_y: usize,
-    ) {
-        // I really hope I don't reinvent wheel here... but I probably am
-        for x in destination_x..source_width + destination_x {
-            for y in destination_y..source_height + destination_y {
-                // Either we add here or subtract there so whatever
-                // Maybe we will save some microseconds, or maybe compiler optimizes it anyway idk
-                let source_x = x - destination_x;
-                let source_y = y - destination_y;
-                let destination_position = y * destination_width + x;
-                let source_position = source_y * source_width + source_x;
-                destination[destination_position] = source[source_position];
-            }
+    ) -> Result<(), Error> {
+        if source.len() < source_width * source_height {
+            return Err(Error::BufferTooSmall(
+                source.len(),
+                source_width,
+                source_height,
+            ));
+        }
+        if destination.len() < destination_width * destination_height {
+            return Err(Error::BufferTooSmall(
+                destination.len(),
+                destination_width,
+                destination_height,
+            ));
+        }
+        let copy_width = source_width.min(destination_width.saturating_sub(destination_x));
+        let copy_height = source_height.min(destination_height.saturating_sub(destination_y));
+        // Source is completely outside of destination, its position may not even be valid index
+        if copy_width == 0 || copy_height == 0 {
+            return Ok(());
         }
+        // Whole rows can be copied at once, as both images are stored row by row
+        for source_y in 0..copy_height {
+            let source_position = source_y * source_width;
+            let destination_position =
+                (source_y + destination_y) * destination_width + destination_x;
+            destination[destination_position..destination_position + copy_width]
+                .copy_from_slice(&source[source_position..source_position + copy_width]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_crops_at_edges() {
+        let source = [1_u8; 4 * 4];
+        let mut destination = [0_u8; 6 * 6];
+        PngHelper::copy_from_to(&source, 4, 4, &mut destination, 6, 6, 4, 3).unwrap();
+        let copied: Vec<usize> = (0..destination.len())
+            .filter(|position| destination[*position] == 1)
+            .collect();
+        assert_eq!(copied, vec![22, 23, 28, 29, 34, 35]);
+    }
+
+    #[test]
+    fn copy_outside_destination_is_noop() {
+        let source = [1_u8; 4 * 4];
+        let mut destination = [0_u8; 10 * 10];
+        PngHelper::copy_from_to(&source, 4, 4, &mut destination, 10, 10, 15, 9).unwrap();
+        PngHelper::copy_from_to(&source, 4, 4, &mut destination, 10, 10, 0, 10).unwrap();
+        assert!(destination.iter().all(|index| *index == 0));
+    }
+
+    #[test]
+    fn copy_checks_buffer_sizes() {
+        let mut destination = [0_u8; 10];
+        assert!(PngHelper::copy_from_to(&[1; 3], 2, 2, &mut destination, 2, 2, 0, 0).is_err());
+        assert!(PngHelper::copy_from_to(&[1; 4], 2, 2, &mut destination, 4, 4, 0, 0).is_err());
     }
 }
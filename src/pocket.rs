@@ -0,0 +1,430 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use thiserror::Error;
+
+use crate::emulator_formats::{Converter, Format};
+use crate::helpers::Helpers;
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+use crate::palette::{self, Palette};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("SD card path {0:?} does not exist or is not a directory")]
+    NotFound(PathBuf),
+    #[error("Invalid palette {0}: {1}")]
+    InvalidPalette(String, palette::Error),
+    #[error("There is no screenshots folder {0:?} on SD card")]
+    NoScreenshots(PathBuf),
+    #[error("Error while accessing SD card: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// What to do when file with the same name, but different content, is already on SD card
+#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+pub enum OnCollision {
+    /// Install as name_1.pal, name_2.pal...
+    #[default]
+    Rename,
+    Overwrite,
+    Skip,
+}
+
+/// Mounted Analogue Pocket SD card, or any folder with the same layout
+pub struct Pocket {
+    root: PathBuf,
+}
+
+impl Pocket {
+    /// Where Analogue OS looks for custom GB palettes
+    pub const PALETTES_DIR: &'static str = "Assets/gb/common/palettes";
+    pub const SCREENSHOTS_DIR: &'static str = "Memories/Screenshots";
+
+    pub fn new(root: &str) -> Result<Self, Error> {
+        let root = PathBuf::from(root);
+        if !root.is_dir() {
+            return Err(Error::NotFound(root));
+        }
+        if !root.join("Assets").is_dir() && !root.join("Memories").is_dir() {
+            warn!("{root:?} has no Assets or Memories folder, are you sure it is Pocket SD card?");
+        }
+        Ok(Self { root })
+    }
+
+    pub fn palettes_dir(&self) -> PathBuf {
+        self.root.join(Self::PALETTES_DIR)
+    }
+
+    pub fn screenshots_dir(&self) -> PathBuf {
+        self.root.join(Self::SCREENSHOTS_DIR)
+    }
+
+    /// Palette file as it should be written on SD card, built-in palettes are exported
+    fn pal_data(pal_file: &str) -> Result<Vec<u8>, Error> {
+        let palette = Palette::load(pal_file)
+            .map_err(|err| Error::InvalidPalette(pal_file.to_string(), err))?;
+        if Path::new(pal_file).is_file() {
+            Ok(fs::read(pal_file)?)
+        } else {
+            Ok(Converter::export(&palette, Format::Pocket))
+        }
+    }
+
+    /// `name.ext` -> `name_1.ext`, `name_2.ext`... whichever does not exist yet,
+    /// or None if one of them already has the same data
+    fn free_name(file: &Path, data: &[u8]) -> Result<Option<PathBuf>, Error> {
+        let stem = file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let extension = file
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        for counter in 1.. {
+            let candidate = file.with_file_name(format!("{stem}_{counter}{extension}"));
+            if !candidate.exists() {
+                return Ok(Some(candidate));
+            }
+            if fs::read(&candidate)? == data {
+                debug!("{candidate:?} is already on SD card");
+                return Ok(None);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Write data to target, returns path actually written (if any)
+    fn write_file(
+        target: &Path,
+        data: &[u8],
+        on_collision: OnCollision,
+    ) -> Result<Option<PathBuf>, Error> {
+        let target = if target.exists() {
+            if fs::read(target)? == data {
+                debug!("{target:?} is already on SD card");
+                return Ok(None);
+            }
+            match on_collision {
+                OnCollision::Rename => match Self::free_name(target, data)? {
+                    Some(free_name) => free_name,
+                    None => return Ok(None),
+                },
+                OnCollision::Overwrite => target.to_path_buf(),
+                OnCollision::Skip => {
+                    warn!("Skipping {target:?}, different file with this name already exists");
+                    return Ok(None);
+                }
+            }
+        } else {
+            target.to_path_buf()
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&target, data)?;
+        Ok(Some(target))
+    }
+
+    /// Copy palettes into palettes folder, returns number of installed ones
+    pub fn install(&self, pal_files: &[String], on_collision: OnCollision) -> Result<usize, Error> {
+        let mut installed = 0;
+        for pal_file in pal_files {
+            let data = match Self::pal_data(pal_file) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Skipping {err}");
+                    continue;
+                }
+            };
+            let file_name = Path::new(pal_file.trim_start_matches(Library::PREFIX))
+                .with_extension(Format::Pocket.extension());
+            let file_name = file_name.file_name().unwrap_or_default();
+            let target = self.palettes_dir().join(file_name);
+            if let Some(written) = Self::write_file(&target, &data, on_collision)? {
+                info!("Installed {pal_file} as {written:?}");
+                if let Some(text) = PaletteMetadata::sidecar_text(pal_file) {
+                    fs::write(PaletteMetadata::sidecar_file(&written), text)?;
+                }
+                installed += 1;
+            }
+        }
+        Ok(installed)
+    }
+
+    /// Installed .pal files, including ones in subfolders
+    pub fn palettes(&self) -> Vec<String> {
+        let pattern = self.palettes_dir().join("**/*.pal");
+        let mut palettes = Helpers::glob_paths(&[pattern.to_string_lossy().to_string()]);
+        palettes.sort();
+        palettes
+    }
+
+    /// Copy screenshots not imported yet into `library_dir/YYYY-MM-DD/`, by file modification date.
+    ///
+    /// Returns number of imported screenshots
+    pub fn import_screenshots(&self, library_dir: &str) -> Result<usize, Error> {
+        let screenshots_dir = self.screenshots_dir();
+        if !screenshots_dir.is_dir() {
+            return Err(Error::NoScreenshots(screenshots_dir));
+        }
+        let mut imported = 0;
+        let mut entries: Vec<_> = fs::read_dir(&screenshots_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+            })
+            .collect();
+        entries.sort();
+        for screenshot in entries {
+            let modified: DateTime<Local> = fs::metadata(&screenshot)?.modified()?.into();
+            let target = Path::new(library_dir)
+                .join(modified.format("%Y-%m-%d").to_string())
+                .join(screenshot.file_name().unwrap_or_default());
+            let data = fs::read(&screenshot)?;
+            if let Some(written) = Self::write_file(&target, &data, OnCollision::Rename)? {
+                info!("Imported {screenshot:?} as {written:?}");
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Make palettes folder the same as source folder: new and changed palettes are copied,
+    /// palettes missing in source are removed if `delete` is set, otherwise only reported.
+    /// Metadata sidecar is part of palette, it is copied and removed together with it.
+    ///
+    /// With `dry_run` nothing is written, only logged
+    pub fn sync(&self, source_dir: &str, delete: bool, dry_run: bool) -> Result<(), Error> {
+        let pattern = Path::new(source_dir).join("**/*.pal");
+        let sources = Helpers::glob_paths(&[pattern.to_string_lossy().to_string()]);
+        let palettes_dir = self.palettes_dir();
+        let mut expected = Vec::with_capacity(sources.len());
+        let (mut copied, mut removed) = (0, 0);
+        for source in &sources {
+            let relative = Path::new(source)
+                .strip_prefix(source_dir)
+                .unwrap_or(Path::new(source));
+            let target = palettes_dir.join(relative);
+            expected.push(target.clone());
+            let data = match Self::pal_data(source) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Skipping {err}");
+                    continue;
+                }
+            };
+            let sidecar = PaletteMetadata::sidecar_file(&target);
+            let sidecar_text = PaletteMetadata::sidecar_text(source);
+            let palette_changed = !target.exists() || fs::read(&target)? != data;
+            let sidecar_changed = fs::read_to_string(&sidecar).ok() != sidecar_text;
+            if !palette_changed && !sidecar_changed {
+                continue;
+            }
+            info!("Copying {source} to {target:?}");
+            if !dry_run {
+                if palette_changed {
+                    Self::write_file(&target, &data, OnCollision::Overwrite)?;
+                }
+                match &sidecar_text {
+                    Some(text) => fs::write(&sidecar, text)?,
+                    None if sidecar.exists() => fs::remove_file(&sidecar)?,
+                    None => {}
+                }
+            }
+            copied += 1;
+        }
+        for installed in self.palettes() {
+            let installed = PathBuf::from(installed);
+            if expected.contains(&installed) {
+                continue;
+            }
+            if delete {
+                info!("Removing {installed:?}");
+                let sidecar = PaletteMetadata::sidecar_file(&installed);
+                if !dry_run {
+                    fs::remove_file(&installed)?;
+                    if sidecar.exists() {
+                        fs::remove_file(&sidecar)?;
+                    }
+                }
+                removed += 1;
+            } else {
+                warn!("{installed:?} is not in {source_dir}, use --delete to remove it");
+            }
+        }
+        info!(
+            "{}{copied} palette(s) copied, {removed} removed",
+            if dry_run { "Dry run: " } else { "" }
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn path(dir: &Path, file: &str) -> String {
+        dir.join(file).to_string_lossy().to_string()
+    }
+
+    /// Empty SD card, and folder with two palettes, one of them with metadata sidecar
+    fn setup() -> (TempDir, Pocket, TempDir) {
+        let card = TempDir::new().unwrap();
+        fs::create_dir_all(card.path().join(Pocket::PALETTES_DIR)).unwrap();
+        let pocket = Pocket::new(card.path().to_str().unwrap()).unwrap();
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join("sub")).unwrap();
+        Palette::default().save(&path(source.path(), "a.pal"));
+        Library::get("dmg")
+            .unwrap()
+            .palette
+            .save(&path(source.path(), "sub/b.pal"));
+        fs::write(
+            source.path().join("sub/b.pal.toml"),
+            "author = \"Someone\"\n",
+        )
+        .unwrap();
+        (card, pocket, source)
+    }
+
+    #[test]
+    fn detects_sd_card() {
+        let card = TempDir::new().unwrap();
+        let file = path(card.path(), "file.txt");
+        fs::write(&file, "").unwrap();
+        assert!(matches!(Pocket::new(&file), Err(Error::NotFound(_))));
+        assert!(matches!(
+            Pocket::new(&path(card.path(), "missing")),
+            Err(Error::NotFound(_))
+        ));
+        // Folders without Pocket layout are accepted too, only with warning
+        let pocket = Pocket::new(card.path().to_str().unwrap()).unwrap();
+        assert_eq!(
+            pocket.palettes_dir(),
+            card.path().join("Assets/gb/common/palettes")
+        );
+        assert!(pocket.palettes().is_empty());
+    }
+
+    #[test]
+    fn installs_palettes() {
+        let (_card, pocket, source) = setup();
+        let pal_files = [
+            path(source.path(), "a.pal"),
+            path(source.path(), "sub/b.pal"),
+            "builtin:pocket".to_string(),
+            path(source.path(), "missing.pal"),
+        ];
+        assert_eq!(pocket.install(&pal_files, OnCollision::Rename).unwrap(), 3);
+        let installed = pocket.palettes_dir();
+        assert!(installed.join("a.pal").is_file());
+        assert!(installed.join("pocket.pal").is_file());
+        let sidecar = fs::read_to_string(installed.join("b.pal.toml")).unwrap();
+        assert!(sidecar.contains("Someone"));
+        // The same palettes are not installed again
+        assert_eq!(pocket.install(&pal_files, OnCollision::Rename).unwrap(), 0);
+    }
+
+    #[test]
+    fn installs_colliding_palettes() {
+        let (_card, pocket, source) = setup();
+        let existing = pocket.palettes_dir().join("a.pal");
+        fs::write(
+            &existing,
+            Converter::export(&Palette::default(), Format::Pocket),
+        )
+        .unwrap();
+        let other = TempDir::new().unwrap();
+        let other_a = path(other.path(), "a.pal");
+        Library::get("light").unwrap().palette.save(&other_a);
+        let other_a = [other_a];
+
+        assert_eq!(pocket.install(&other_a, OnCollision::Skip).unwrap(), 0);
+        assert_eq!(pocket.install(&other_a, OnCollision::Rename).unwrap(), 1);
+        assert_eq!(pocket.install(&other_a, OnCollision::Rename).unwrap(), 0);
+        assert_eq!(
+            fs::read(pocket.palettes_dir().join("a_1.pal")).unwrap(),
+            fs::read(&other_a[0]).unwrap()
+        );
+        assert_eq!(pocket.install(&other_a, OnCollision::Overwrite).unwrap(), 1);
+        assert_eq!(fs::read(&existing).unwrap(), fs::read(&other_a[0]).unwrap());
+        assert_ne!(
+            fs::read(&existing).unwrap(),
+            fs::read(path(source.path(), "a.pal")).unwrap()
+        );
+    }
+
+    #[test]
+    fn syncs_palettes() {
+        let (_card, pocket, source) = setup();
+        let source_dir = source.path().to_str().unwrap();
+        let installed = pocket.palettes_dir();
+        let extra = installed.join("extra.pal");
+        fs::write(&extra, fs::read(path(source.path(), "a.pal")).unwrap()).unwrap();
+        fs::write(
+            PaletteMetadata::sidecar_file(&extra),
+            "author = \"Other\"\n",
+        )
+        .unwrap();
+
+        pocket.sync(source_dir, true, true).unwrap();
+        assert!(!installed.join("a.pal").exists());
+        assert!(extra.exists());
+
+        pocket.sync(source_dir, false, false).unwrap();
+        assert!(installed.join("a.pal").is_file());
+        assert!(installed.join("sub/b.pal").is_file());
+        assert!(installed.join("sub/b.pal.toml").is_file());
+        assert!(extra.exists());
+
+        // Removed sidecar is removed from SD card too
+        fs::remove_file(source.path().join("sub/b.pal.toml")).unwrap();
+        pocket.sync(source_dir, true, false).unwrap();
+        assert!(!installed.join("sub/b.pal.toml").exists());
+        assert!(!extra.exists());
+        assert!(!PaletteMetadata::sidecar_file(&extra).exists());
+        assert_eq!(pocket.palettes().len(), 2);
+    }
+
+    #[test]
+    fn imports_screenshots() {
+        let (card, pocket, _source) = setup();
+        let library = TempDir::new().unwrap();
+        let library_dir = library.path().to_str().unwrap();
+        assert!(matches!(
+            pocket.import_screenshots(library_dir),
+            Err(Error::NoScreenshots(_))
+        ));
+
+        let screenshots = card.path().join(Pocket::SCREENSHOTS_DIR);
+        fs::create_dir_all(&screenshots).unwrap();
+        fs::write(screenshots.join("1.png"), "first").unwrap();
+        fs::write(screenshots.join("2.PNG"), "second").unwrap();
+        fs::write(screenshots.join("notes.txt"), "").unwrap();
+        assert_eq!(pocket.import_screenshots(library_dir).unwrap(), 2);
+        assert_eq!(pocket.import_screenshots(library_dir).unwrap(), 0);
+
+        let modified: DateTime<Local> = fs::metadata(screenshots.join("1.png"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .into();
+        let day = library.path().join(modified.format("%Y-%m-%d").to_string());
+        assert_eq!(fs::read_to_string(day.join("1.png")).unwrap(), "first");
+        // Different screenshot with the same name is imported under new name
+        fs::write(screenshots.join("1.png"), "other").unwrap();
+        assert_eq!(pocket.import_screenshots(library_dir).unwrap(), 1);
+        assert_eq!(fs::read_to_string(day.join("1_1.png")).unwrap(), "other");
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use image::io::Reader;
+use image::{DynamicImage, ImageError};
+use log::{debug, warn};
+
+use crate::helpers::Helpers;
+use crate::image_handler::ImageHandler;
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+use crate::palette::{Color, Palette};
+
+/// What palette screenshot was most likely taken with
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScreenshotKind {
+    /// Taken with template palette, can be colorized
+    Template,
+    /// Taken with different template palette of given name
+    OtherTemplate(String),
+    /// Already colorized, with known palette of given name
+    Colorized(String),
+    /// Colors do not match any known palette, number of unique colors is kept for reporting
+    Unknown(usize),
+}
+
+impl Display for ScreenshotKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScreenshotKind::Template => write!(f, "template screenshot"),
+            ScreenshotKind::OtherTemplate(name) => {
+                write!(f, "taken with template {name}, use --template {name}")
+            }
+            ScreenshotKind::Colorized(name) => write!(
+                f,
+                "already colorized with {name}, use --taken-with {name} to recolor it"
+            ),
+            ScreenshotKind::Unknown(colors) if *colors > ScreenshotClassifier::MAX_COLORS => {
+                write!(
+                    f,
+                    "unknown, more than {} colors",
+                    ScreenshotClassifier::MAX_COLORS
+                )
+            }
+            ScreenshotKind::Unknown(colors) => {
+                write!(f, "unknown, {colors} colors not matching any known palette")
+            }
+        }
+    }
+}
+
+/// Classifies screenshots by comparing their colors with color sets of template and known palettes
+pub struct ScreenshotClassifier {
+    template: HashSet<Color>,
+    /// Name, colors and if palette is template (tagged as such in metadata)
+    known: Vec<(String, HashSet<Color>, bool)>,
+}
+
+impl ScreenshotClassifier {
+    /// Paletted Game Boy screenshot cannot have more colors than that
+    pub const MAX_COLORS: usize = Palette::SLOTS;
+    /// Metadata tag of template palettes, see create-template-pal
+    pub const TEMPLATE_TAG: &'static str = "template";
+
+    /// Built-in palettes are always known, `pal_files` (globs) are added to them
+    pub fn new(template: &Palette, pal_files: &[String]) -> Self {
+        let template: HashSet<Color> = template.clone().into();
+        // User palettes go first, so they win over built-in ones with the same colors
+        let mut known_files = if pal_files.is_empty() {
+            Vec::new()
+        } else {
+            Helpers::glob_paths(pal_files)
+        };
+        known_files.extend(Library::glob("*"));
+        let known = Self::load_known(&template, &known_files);
+        Self { template, known }
+    }
+
+    fn load_known(
+        template: &HashSet<Color>,
+        pal_files: &[String],
+    ) -> Vec<(String, HashSet<Color>, bool)> {
+        pal_files
+            .iter()
+            .filter_map(|pal_file| match Palette::load(pal_file) {
+                Ok(palette) => Some((
+                    pal_file.clone(),
+                    HashSet::from(palette),
+                    PaletteMetadata::load(pal_file).has_tag(Self::TEMPLATE_TAG),
+                )),
+                Err(err) => {
+                    warn!("Cannot use {pal_file} for screenshot detection: {err}");
+                    None
+                }
+            })
+            .filter(|(_, colors, _)| colors != template)
+            .collect()
+    }
+
+    /// Unique colors of image, stops counting after [`Self::MAX_COLORS`] is exceeded
+    fn colors(image: &DynamicImage) -> HashSet<Color> {
+        let mut colors = HashSet::new();
+        for pixel in image.to_rgb8().pixels() {
+            colors.insert(pixel.0);
+            if colors.len() > Self::MAX_COLORS {
+                break;
+            }
+        }
+        colors
+    }
+
+    /// All image colors are in palette colors, with the same tolerance as used for colorizing
+    fn all_in(image_colors: &HashSet<Color>, palette_colors: &HashSet<Color>) -> bool {
+        let tolerance = ImageHandler::TEMPLATE_TOLERANCE;
+        image_colors.iter().all(|color| {
+            palette_colors.contains(color)
+                || palette_colors.iter().any(|palette_color| {
+                    palette_color
+                        .iter()
+                        .zip(color)
+                        .all(|(a, b)| a.abs_diff(*b) <= tolerance)
+                })
+        })
+    }
+
+    pub fn classify(&self, image: &DynamicImage) -> ScreenshotKind {
+        let colors = Self::colors(image);
+        if colors.len() > Self::MAX_COLORS {
+            return ScreenshotKind::Unknown(colors.len());
+        }
+        if Self::all_in(&colors, &self.template) {
+            return ScreenshotKind::Template;
+        }
+        // Smallest matching palette is the most specific one, e.g. DMG green over GBC palette using it
+        self.known
+            .iter()
+            .filter(|(_, palette_colors, _)| Self::all_in(&colors, palette_colors))
+            .min_by_key(|(_, palette_colors, _)| palette_colors.len())
+            .map(|(name, _, is_template)| match is_template {
+                true => ScreenshotKind::OtherTemplate(name.clone()),
+                false => ScreenshotKind::Colorized(name.clone()),
+            })
+            .unwrap_or(ScreenshotKind::Unknown(colors.len()))
+    }
+
+    pub fn classify_file(&self, image_path: &str) -> Result<ScreenshotKind, ImageError> {
+        let image = Reader::open(image_path)?.decode()?;
+        let kind = self.classify(&image);
+        debug!("Screenshot {image_path} is {kind}");
+        Ok(kind)
+    }
+}
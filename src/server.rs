@@ -0,0 +1,593 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path};
+
+use image::io::Reader;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use thiserror::Error;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::emulator_formats::{Converter, Format};
+use crate::helpers::Helpers;
+use crate::image_handler::{ImageHandler, TEMPLATES};
+use crate::library::Library;
+use crate::metadata::PaletteMetadata;
+use crate::palette::{Color, ColorExt, Palette};
+use crate::png_helper::{PngHelper, PngPalette};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl Error {
+    fn status(&self) -> u16 {
+        match self {
+            Error::NotFound(_) => 404,
+            Error::BadRequest(_) => 400,
+            Error::Forbidden(_) => 403,
+            Error::Internal(_) => 500,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct PaletteInfo {
+    name: String,
+    path: String,
+    /// Hex colors in slot order, see [`Palette::SLOTS`]
+    colors: Vec<String>,
+    metadata: PaletteMetadata,
+}
+
+#[derive(Deserialize, Debug)]
+struct ColorizeRequest {
+    screenshot: String,
+    /// Path of palette to use, one of listed by `GET /palettes`
+    palette: Option<String>,
+    /// Or palette colors directly, as 17 hex values in slot order
+    colors: Option<Vec<String>>,
+    scale: Option<u8>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SavePaletteRequest {
+    path: String,
+    colors: Vec<String>,
+}
+
+/// Local HTTP server for previewing and editing palettes in browser
+pub struct Server {
+    pal_files: Vec<String>,
+    input_images: Vec<String>,
+    default_scale: u8,
+    save_dir: Option<String>,
+    /// Template palette screenshots were taken with
+    template: Palette,
+    /// Port we listen on, requests for other hosts are not trusted
+    port: u16,
+    /// Screenshots already encoded as paletted .png with template palette, by path and scale.
+    /// Colorizing is then just swapping PLTE chunk. Only last used scale of each screenshot is kept.
+    encoded: HashMap<(String, u8), Vec<u8>>,
+}
+
+impl Server {
+    /// Larger images take long to encode and are of no use in browser
+    const MAX_SCALE: u8 = 8;
+    /// Requests are small JSON documents, .pal file itself is only 56 bytes
+    const MAX_BODY: u64 = 64 * 1024;
+
+    pub fn new(
+        pal_files: &[String],
+        input_images: &[String],
+        default_scale: Option<u8>,
+        save_dir: Option<String>,
+        template: Palette,
+    ) -> Self {
+        Self {
+            pal_files: pal_files.to_vec(),
+            input_images: input_images.to_vec(),
+            default_scale: default_scale.unwrap_or(3),
+            save_dir,
+            template,
+            port: 0,
+            encoded: HashMap::new(),
+        }
+    }
+
+    /// Start listening on localhost, never returns unless server cannot be started
+    pub fn run(&mut self, port: u16) {
+        let address = format!("127.0.0.1:{port}");
+        let server = tiny_http::Server::http(&address)
+            .unwrap_or_else(|err| panic!("Cannot start server on {address}: {err}"));
+        self.serve(server);
+    }
+
+    /// Handle requests of already started server (e.g. one on port 0), never returns
+    pub fn serve(&mut self, server: tiny_http::Server) {
+        self.port = server
+            .server_addr()
+            .to_ip()
+            .map(|address| address.port())
+            .unwrap_or_default();
+        info!("Listening on http://127.0.0.1:{}/", self.port);
+        for request in server.incoming_requests() {
+            self.handle(request);
+        }
+    }
+
+    /// Body of request, at most [`Self::MAX_BODY`] bytes
+    fn read_body(request: &mut Request) -> Result<String, Error> {
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(Self::MAX_BODY + 1)
+            .read_to_string(&mut body)
+            .map_err(|err| Error::BadRequest(format!("Cannot read request body: {err}")))?;
+        if body.len() as u64 > Self::MAX_BODY {
+            return Err(Error::BadRequest(format!(
+                "Request body is larger than {} bytes",
+                Self::MAX_BODY
+            )));
+        }
+        Ok(body)
+    }
+
+    fn handle(&mut self, mut request: Request) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (Helpers::url_decode(key), Helpers::url_decode(value)))
+            .collect();
+        let body = Self::read_body(&mut request);
+        debug!("{} {}", request.method(), url);
+
+        let result = body.and_then(|body| match (request.method(), path) {
+            (Method::Get, "/") => self
+                .index()
+                .map(|html| (html.into_bytes(), "text/html; charset=utf-8")),
+            (Method::Get, "/palettes") => self
+                .palettes_json()
+                .map(|json| (json.into_bytes(), "application/json")),
+            (Method::Post, "/palettes") => self
+                .check_write_request(&request)
+                .and_then(|_| self.save_palette(&body))
+                .map(|json| (json.into_bytes(), "application/json")),
+            (Method::Get, "/screenshots") => Ok((
+                serde_json::to_vec(&self.screenshots()).unwrap(),
+                "application/json",
+            )),
+            (Method::Get, "/colorize") => {
+                let colorize_request = ColorizeRequest {
+                    screenshot: query.get("screenshot").cloned().unwrap_or_default(),
+                    palette: query.get("palette").cloned(),
+                    colors: None,
+                    scale: query.get("scale").and_then(|scale| scale.parse().ok()),
+                };
+                self.colorize(colorize_request)
+                    .map(|png| (png, "image/png"))
+            }
+            (Method::Post, "/colorize") => serde_json::from_str(&body)
+                .map_err(|err| Error::BadRequest(err.to_string()))
+                .and_then(|colorize_request| self.colorize(colorize_request))
+                .map(|png| (png, "image/png")),
+            _ => Err(Error::NotFound(path.to_string())),
+        });
+
+        let response = match result {
+            Ok((data, content_type)) => Response::from_data(data)
+                .with_header(Header::from_bytes("Content-Type", content_type).unwrap()),
+            Err(err) => {
+                warn!("{} {} failed: {}", request.method(), url, err);
+                Response::from_data(err.to_string().into_bytes()).with_status_code(err.status())
+            }
+        };
+        if let Err(err) = request.respond(response) {
+            warn!("Cannot send response: {err}");
+        }
+    }
+
+    fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str())
+    }
+
+    /// Requests which write files must come from our own page, not from other sites
+    /// (by cross-site form or DNS rebinding), and must be JSON, which forms cannot send
+    fn check_write_request(&self, request: &Request) -> Result<(), Error> {
+        let hosts = [
+            format!("127.0.0.1:{}", self.port),
+            format!("localhost:{}", self.port),
+        ];
+        let host = Self::header(request, "Host").unwrap_or_default();
+        if !hosts.iter().any(|allowed| allowed == host) {
+            return Err(Error::Forbidden(format!("Host '{host}' is not allowed")));
+        }
+        if let Some(origin) = Self::header(request, "Origin") {
+            let allowed = hosts
+                .iter()
+                .any(|allowed| origin == format!("http://{allowed}"));
+            if !allowed {
+                return Err(Error::Forbidden(format!(
+                    "Origin '{origin}' is not allowed"
+                )));
+            }
+        }
+        let content_type = Self::header(request, "Content-Type").unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !media_type.eq_ignore_ascii_case("application/json") {
+            return Err(Error::BadRequest(format!(
+                "Content-Type must be application/json, not '{content_type}'"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Palettes are globbed on each request, so newly created files show up without restart
+    fn palettes(&self) -> Vec<String> {
+        Helpers::glob_paths(&self.pal_files)
+    }
+
+    fn screenshots(&self) -> Vec<String> {
+        Helpers::glob_paths(&self.input_images)
+    }
+
+    fn palette_info(path: &str) -> Option<PaletteInfo> {
+        let palette = Palette::load(path)
+            .map_err(|err| warn!("Skipping palette {path}: {err}"))
+            .ok()?;
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+        Some(PaletteInfo {
+            name,
+            path: path.to_string(),
+            colors: (0..Palette::SLOTS)
+                .map(|slot| palette.slot(slot).to_hex())
+                .collect(),
+            metadata: PaletteMetadata::load(path),
+        })
+    }
+
+    fn index(&self) -> Result<String, Error> {
+        let palettes: Vec<PaletteInfo> = self
+            .palettes()
+            .iter()
+            .filter_map(|path| Self::palette_info(path))
+            .collect();
+        let slot_names: Vec<String> = (0..Palette::SLOTS).map(Palette::slot_name).collect();
+        let mut context = Context::new();
+        context.insert("version", env!("GIT_HASH_SHORT"));
+        context.insert("palettes", &palettes);
+        context.insert("screenshots", &self.screenshots());
+        context.insert("slot_names", &slot_names);
+        context.insert("scale", &self.default_scale);
+        context.insert("palettes_json", &Self::script_json(&palettes));
+        context.insert("save_dir_json", &Self::script_json(&self.save_dir));
+        TEMPLATES
+            .render("serve.html", &context)
+            .map_err(|err| Error::BadRequest(format!("Cannot render template: {err}")))
+    }
+
+    /// JSON for use inside `<script>`, where HTML is not escaped,
+    /// so '<' is escaped instead and metadata cannot close the script
+    fn script_json<T: Serialize>(value: &T) -> String {
+        serde_json::to_string(value)
+            .unwrap()
+            .replace('<', "\\u003c")
+    }
+
+    fn palettes_json(&self) -> Result<String, Error> {
+        let palettes: Vec<PaletteInfo> = self
+            .palettes()
+            .iter()
+            .filter_map(|path| Self::palette_info(path))
+            .collect();
+        Ok(serde_json::to_string(&palettes).unwrap())
+    }
+
+    fn palette_from_colors(colors: &[String]) -> Result<Palette, Error> {
+        if colors.len() != Palette::SLOTS {
+            return Err(Error::BadRequest(format!(
+                "Expected {} colors, got {}",
+                Palette::SLOTS,
+                colors.len()
+            )));
+        }
+        let mut palette = Palette::default();
+        for (slot, hex) in colors.iter().enumerate() {
+            let color = Color::from_hex(hex)
+                .ok_or_else(|| Error::BadRequest(format!("Invalid color '{hex}'")))?;
+            palette.set_slot(slot, color);
+        }
+        Ok(palette)
+    }
+
+    fn colorize(&mut self, request: ColorizeRequest) -> Result<Vec<u8>, Error> {
+        // Only files we were asked to serve can be read, not anything user asks for
+        if !self.screenshots().contains(&request.screenshot) {
+            return Err(Error::NotFound(format!(
+                "screenshot '{}'",
+                request.screenshot
+            )));
+        }
+        let palette = match (&request.palette, &request.colors) {
+            (_, Some(colors)) => Self::palette_from_colors(colors)?,
+            (Some(palette), None) => {
+                if !self.palettes().contains(palette) {
+                    return Err(Error::NotFound(format!("palette '{palette}'")));
+                }
+                Palette::load(palette).map_err(|err| Error::BadRequest(err.to_string()))?
+            }
+            (None, None) => {
+                return Err(Error::BadRequest("palette or colors required".to_string()))
+            }
+        };
+        let scale = request
+            .scale
+            .unwrap_or(self.default_scale)
+            .clamp(1, Self::MAX_SCALE);
+        let key = (request.screenshot.clone(), scale);
+        if !self.encoded.contains_key(&key) {
+            let image = Reader::open(&request.screenshot)
+                .map_err(|err| Error::Internal(err.to_string()))?
+                .decode()
+                .map_err(|err| Error::BadRequest(format!("Cannot decode screenshot: {err}")))?;
+            let (width, height) = (image.width() as usize, image.height() as usize);
+            let unscaled = ImageHandler::palettize_image(self.template.clone(), &image);
+            let scaled =
+                ImageHandler::scale_paletted_image(&unscaled, width, height, scale as usize);
+            let pal: PngPalette = self.template.clone().into();
+            let pal: [u8; 256 * 3] = pal.into();
+            let encoded = PngHelper::encode(
+                (width * scale as usize) as u32,
+                (height * scale as usize) as u32,
+                &pal,
+                &scaled,
+            );
+            self.encoded
+                .retain(|(screenshot, _), _| *screenshot != request.screenshot);
+            self.encoded.insert(key.clone(), encoded);
+        }
+        let encoded = &self.encoded[&key];
+        let pal: PngPalette = palette.into();
+        let pal: [u8; 256 * 3] = pal.into();
+        PngHelper::replace_palette(encoded, &pal).map_err(|err| Error::BadRequest(err.to_string()))
+    }
+
+    /// Existing palettes can be overwritten, new ones can only be created inside save directory
+    fn save_palette(&self, body: &str) -> Result<String, Error> {
+        let request: SavePaletteRequest =
+            serde_json::from_str(body).map_err(|err| Error::BadRequest(err.to_string()))?;
+        let path = Path::new(&request.path);
+        let allowed = if Library::is_builtin(&request.path) {
+            false
+        } else if self.palettes().contains(&request.path) {
+            true
+        } else if let Some(save_dir) = &self.save_dir {
+            path.starts_with(save_dir)
+                && path.extension().is_some_and(|extension| extension == "pal")
+                && !path.components().any(|c| c == Component::ParentDir)
+        } else {
+            false
+        };
+        if !allowed {
+            return Err(Error::Forbidden(format!(
+                "'{}' is not listed palette nor .pal file inside save directory",
+                request.path
+            )));
+        }
+        let palette = Self::palette_from_colors(&request.colors)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| Error::Internal(err.to_string()))?;
+        }
+        fs::write(&request.path, Converter::export(&palette, Format::Pocket))
+            .map_err(|err| Error::Internal(format!("Cannot write {}: {err}", request.path)))?;
+        info!("Saved palette {}", request.path);
+        Self::palette_info(&request.path)
+            .map(|info| serde_json::to_string(&info).unwrap())
+            .ok_or(Error::NotFound(request.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::thread;
+
+    use image::RgbImage;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Server with one palette and one screenshot taken with `template`, returns its port
+    fn start(dir: &TempDir, template: Palette) -> u16 {
+        let pal_file = dir.path().join("palettes/test.pal");
+        fs::create_dir_all(pal_file.parent().unwrap()).unwrap();
+        fs::write(
+            &pal_file,
+            Converter::export(&Palette::default(), Format::Pocket),
+        )
+        .unwrap();
+        let screenshot = RgbImage::from_fn(Palette::SLOTS as u32, 1, |x, _| {
+            image::Rgb(template.slot(x as usize))
+        });
+        screenshot.save(dir.path().join("shot.png")).unwrap();
+        let root = dir.path().to_string_lossy();
+        let mut server = Server::new(
+            &[format!("{root}/palettes/**/*.pal")],
+            &[format!("{root}/shot.png")],
+            Some(1),
+            Some(format!("{root}/palettes/new")),
+            template,
+        );
+        let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = http.server_addr().to_ip().unwrap().port();
+        thread::spawn(move || server.serve(http));
+        port
+    }
+
+    /// Send request, returns status code and body
+    fn send(port: u16, request_line: &str, headers: &[String], body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut request = format!("{request_line} HTTP/1.1\r\nConnection: close\r\n");
+        for header in headers {
+            request += &format!("{header}\r\n");
+        }
+        request += &format!("Content-Length: {}\r\n\r\n", body.len());
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    fn save_request(dir: &TempDir, colors: &[String]) -> Vec<u8> {
+        let path = dir.path().join("palettes/new/saved.pal");
+        serde_json::to_vec(&serde_json::json!({
+            "path": path.to_string_lossy(),
+            "colors": colors,
+        }))
+        .unwrap()
+    }
+
+    fn colors(palette: &Palette) -> Vec<String> {
+        (0..Palette::SLOTS)
+            .map(|slot| palette.slot(slot).to_hex())
+            .collect()
+    }
+
+    fn inverted_template() -> Palette {
+        let mut template = Palette::default();
+        for slot in 0..Palette::SLOTS {
+            template.set_slot(slot, template.slot(slot).map(|c| 255 - c));
+        }
+        template
+    }
+
+    #[test]
+    fn rejects_other_host() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, Palette::default());
+        let headers = [
+            "Host: evil.example:80".to_string(),
+            "Content-Type: application/json".to_string(),
+        ];
+        let body = save_request(&dir, &colors(&Palette::default()));
+        let (status, _) = send(port, "POST /palettes", &headers, &body);
+        assert_eq!(status, 403);
+    }
+
+    #[test]
+    fn rejects_other_origin() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, Palette::default());
+        let headers = [
+            format!("Host: 127.0.0.1:{port}"),
+            "Origin: http://evil.example".to_string(),
+            "Content-Type: application/json".to_string(),
+        ];
+        let body = save_request(&dir, &colors(&Palette::default()));
+        let (status, _) = send(port, "POST /palettes", &headers, &body);
+        assert_eq!(status, 403);
+    }
+
+    #[test]
+    fn rejects_form_content_type() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, Palette::default());
+        let headers = [
+            format!("Host: localhost:{port}"),
+            "Content-Type: text/plain".to_string(),
+        ];
+        let body = save_request(&dir, &colors(&Palette::default()));
+        let (status, _) = send(port, "POST /palettes", &headers, &body);
+        assert_eq!(status, 400);
+        assert!(!dir.path().join("palettes/new/saved.pal").exists());
+    }
+
+    #[test]
+    fn rejects_large_body() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, Palette::default());
+        let headers = [
+            format!("Host: 127.0.0.1:{port}"),
+            "Content-Type: application/json".to_string(),
+        ];
+        let body = vec![b' '; Server::MAX_BODY as usize + 1];
+        let (status, _) = send(port, "POST /palettes", &headers, &body);
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn saves_palette_and_lists_it() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, Palette::default());
+        let mut palette = Palette::default();
+        palette.set_slot(0, [0x12, 0x34, 0x56]);
+        let headers = [
+            format!("Host: 127.0.0.1:{port}"),
+            format!("Origin: http://127.0.0.1:{port}"),
+            "Content-Type: application/json; charset=utf-8".to_string(),
+        ];
+        let body = save_request(&dir, &colors(&palette));
+        let (status, _) = send(port, "POST /palettes", &headers, &body);
+        assert_eq!(status, 200);
+
+        let saved = dir.path().join("palettes/new/saved.pal");
+        let loaded = Palette::load(&saved.to_string_lossy()).unwrap();
+        assert_eq!(colors(&loaded), colors(&palette));
+        let (status, body) = send(port, "GET /palettes", &[], &[]);
+        assert_eq!(status, 200);
+        let listed: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let listed = listed
+            .iter()
+            .find(|info| info["path"] == saved.to_string_lossy().as_ref())
+            .expect("Saved palette is listed");
+        assert_eq!(listed["colors"], serde_json::json!(colors(&palette)));
+    }
+
+    #[test]
+    fn colorizes_with_custom_template() {
+        let dir = TempDir::new().unwrap();
+        let port = start(&dir, inverted_template());
+        let shot = dir.path().join("shot.png").to_string_lossy().to_string();
+        let body = serde_json::to_vec(&serde_json::json!({
+            "screenshot": shot,
+            "colors": colors(&Palette::default()),
+        }))
+        .unwrap();
+        let (status, png) = send(port, "POST /colorize", &[], &body);
+        assert_eq!(status, 200);
+        let image = image::load_from_memory(&png).unwrap().to_rgb8();
+        for slot in 0..Palette::SLOTS {
+            assert_eq!(
+                image.get_pixel(slot as u32, 0).0,
+                Palette::default().slot(slot)
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::palette::{Color, ColorExt, Colors, Palette};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Error while reading file {0}")]
+    IoError(#[from] io::Error),
+    #[error("SGB packet data must be multiple of 16 bytes, but is {0} bytes")]
+    InvalidPacketData(usize),
+    #[error("Invalid hex data: {0}")]
+    InvalidHex(String),
+    #[error("Hex data must have even number of digits, but has {0} digits")]
+    OddHexDigits(usize),
+    #[error("No PAL01, PAL23, PAL03 or PAL12 packets found")]
+    NoPalettes,
+    #[error("Rule {0:?} needs SGB palettes {1:?}, but only {2:?} were found")]
+    MissingPalettes(SpreadRule, Vec<usize>, Vec<usize>),
+    #[error(
+        "System palettes dump must be exactly {} bytes, but is {0} bytes",
+        SgbPalettes::SYSTEM_DUMP_SIZE
+    )]
+    InvalidDumpSize(usize),
+    #[error("There are only 512 system palettes, {0} is out of range")]
+    InvalidSystemPalette(usize),
+}
+
+/// How 4 colors of SGB palettes are spread across layers of [`Palette`]
+#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+pub enum SpreadRule {
+    /// First palette on all layers, like most SGB games which use single palette
+    #[default]
+    All,
+    /// Palette 0 for background and window, palette 1 for both object layers
+    BgObj,
+    /// Palettes 0, 1, 2, 3 for background, obj0, obj1 and window
+    PerLayer,
+}
+
+impl SpreadRule {
+    /// SGB palettes used by rule
+    fn needs(&self) -> Vec<usize> {
+        match self {
+            SpreadRule::All => vec![0],
+            SpreadRule::BgObj => vec![0, 1],
+            SpreadRule::PerLayer => vec![0, 1, 2, 3],
+        }
+    }
+}
+
+/// Up to 4 SGB palettes of 4 BGR555 colors, as set by palette packets or chosen from system palettes
+#[derive(Debug, Clone, Default)]
+pub struct SgbPalettes {
+    palettes: BTreeMap<usize, [u16; 4]>,
+}
+
+impl SgbPalettes {
+    const PACKET_SIZE: usize = 16;
+    /// 512 palettes of 4 colors, 2 bytes each, as transferred by PAL_TRN
+    pub const SYSTEM_DUMP_SIZE: usize = 512 * 4 * 2;
+
+    fn read_u16(data: &[u8], position: usize) -> u16 {
+        u16::from_le_bytes([data[position], data[position + 1]])
+    }
+
+    /// Raw packet data, or the same as hex text (whitespace and commas are ignored)
+    pub fn read_data(file_name: &str) -> Result<Vec<u8>, Error> {
+        let data = fs::read(file_name)?;
+        let is_text = data
+            .iter()
+            .all(|byte| byte.is_ascii_hexdigit() || byte.is_ascii_whitespace() || *byte == b',');
+        if !is_text || data.is_empty() {
+            return Ok(data);
+        }
+        let digits: Vec<u8> = data
+            .into_iter()
+            .filter(|byte| byte.is_ascii_hexdigit())
+            .collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(Error::OddHexDigits(digits.len()));
+        }
+        digits
+            .chunks(2)
+            .map(|pair| {
+                let pair = String::from_utf8_lossy(pair);
+                u8::from_str_radix(&pair, 16).map_err(|_| Error::InvalidHex(pair.to_string()))
+            })
+            .collect()
+    }
+
+    /// Read PAL01, PAL23, PAL03 and PAL12 packets, other packets are skipped.
+    ///
+    /// Later packets overwrite earlier ones, color 0 is shared by all palettes like on SGB
+    pub fn from_packets(data: &[u8]) -> Result<Self, Error> {
+        if !data.len().is_multiple_of(Self::PACKET_SIZE) {
+            return Err(Error::InvalidPacketData(data.len()));
+        }
+        let mut result = Self::default();
+        let mut color_0 = None;
+        for packet in data.chunks_exact(Self::PACKET_SIZE) {
+            let command = packet[0] >> 3;
+            let (a, b) = match command {
+                0x00 => (0, 1),
+                0x01 => (2, 3),
+                0x02 => (0, 3),
+                0x03 => (1, 2),
+                _ => {
+                    debug!("Skipping SGB packet with command {:#04x}", command);
+                    continue;
+                }
+            };
+            color_0 = Some(Self::read_u16(packet, 1));
+            let colors = |start: usize| {
+                [
+                    0,
+                    Self::read_u16(packet, start),
+                    Self::read_u16(packet, start + 2),
+                    Self::read_u16(packet, start + 4),
+                ]
+            };
+            result.palettes.insert(a, colors(3));
+            result.palettes.insert(b, colors(9));
+        }
+        let Some(color_0) = color_0 else {
+            return Err(Error::NoPalettes);
+        };
+        result
+            .palettes
+            .values_mut()
+            .for_each(|palette| palette[0] = color_0);
+        Ok(result)
+    }
+
+    /// Pick system palettes from PAL_TRN dump, they become SGB palettes 0, 1, 2...
+    pub fn from_system_dump(data: &[u8], indexes: &[usize]) -> Result<Self, Error> {
+        if data.len() != Self::SYSTEM_DUMP_SIZE {
+            return Err(Error::InvalidDumpSize(data.len()));
+        }
+        let mut result = Self::default();
+        for (sgb_palette, index) in indexes.iter().enumerate() {
+            if *index >= 512 {
+                return Err(Error::InvalidSystemPalette(*index));
+            }
+            let start = index * 8;
+            let colors = std::array::from_fn(|i| Self::read_u16(data, start + i * 2));
+            result.palettes.insert(sgb_palette, colors);
+        }
+        Ok(result)
+    }
+
+    /// 24-bit colors of SGB palette
+    pub fn colors(&self, index: usize) -> Option<Colors> {
+        self.palettes
+            .get(&index)
+            .map(|palette| palette.map(Color::from_bgr555))
+    }
+
+    pub fn to_palette(&self, rule: SpreadRule) -> Result<Palette, Error> {
+        let missing: Vec<usize> = rule
+            .needs()
+            .into_iter()
+            .filter(|index| !self.palettes.contains_key(index))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::MissingPalettes(
+                rule,
+                rule.needs(),
+                self.palettes.keys().copied().collect(),
+            ));
+        }
+        let unused = self
+            .palettes
+            .keys()
+            .filter(|index| !rule.needs().contains(index))
+            .join(", ");
+        if !unused.is_empty() {
+            warn!("SGB palettes {unused} are not used by rule {rule:?}");
+        }
+        let palette = |index: usize| self.colors(index).unwrap();
+        let result = match rule {
+            SpreadRule::All => Palette::from_shades(palette(0), palette(0)[0]),
+            SpreadRule::BgObj => Palette::new(
+                palette(0),
+                palette(1),
+                palette(1),
+                palette(0),
+                palette(0)[0],
+            ),
+            SpreadRule::PerLayer => Palette::new(
+                palette(0),
+                palette(1),
+                palette(2),
+                palette(3),
+                palette(0)[0],
+            ),
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    const WHITE: u16 = 0x7FFF;
+    const RED: u16 = 0x001F;
+    const GREEN: u16 = 0x03E0;
+    const BLUE: u16 = 0x7C00;
+
+    /// Palette packet with given command, shared color 0 and colors 1-3 of both palettes
+    fn packet(command: u8, color_0: u16, first: [u16; 3], second: [u16; 3]) -> Vec<u8> {
+        let mut packet = vec![command << 3 | 1];
+        packet.extend(color_0.to_le_bytes());
+        for color in first.into_iter().chain(second) {
+            packet.extend(color.to_le_bytes());
+        }
+        packet.push(0);
+        packet
+    }
+
+    fn read_text(text: &str) -> Result<Vec<u8>, Error> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+        SgbPalettes::read_data(file.path().to_str().unwrap())
+    }
+
+    #[test]
+    fn reads_pal01_packet() {
+        let data = packet(0x00, WHITE, [RED, GREEN, BLUE], [BLUE, GREEN, 0]);
+        let palettes = SgbPalettes::from_packets(&data).unwrap();
+        assert_eq!(
+            palettes.colors(0).unwrap(),
+            [[255, 255, 255], [255, 0, 0], [0, 255, 0], [0, 0, 255]]
+        );
+        assert_eq!(
+            palettes.colors(1).unwrap(),
+            [[255, 255, 255], [0, 0, 255], [0, 255, 0], [0, 0, 0]]
+        );
+        assert!(palettes.colors(2).is_none());
+    }
+
+    #[test]
+    fn later_packets_share_color_0() {
+        let mut data = packet(0x00, WHITE, [RED; 3], [GREEN; 3]);
+        data.extend(packet(0x01, 0, [BLUE; 3], [BLUE; 3]));
+        let palettes = SgbPalettes::from_packets(&data).unwrap();
+        for index in 0..4 {
+            assert_eq!(palettes.colors(index).unwrap()[0], [0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn skips_attr_blk_packet() {
+        // ATTR_BLK with one data set, which would be read as colors if it was not skipped
+        let mut data = vec![0x04 << 3 | 1, 1, 0b111, 0x1B, 0, 0, 19, 17];
+        data.resize(SgbPalettes::PACKET_SIZE, 0);
+        assert!(matches!(
+            SgbPalettes::from_packets(&data),
+            Err(Error::NoPalettes)
+        ));
+        data.extend(packet(0x00, WHITE, [RED; 3], [GREEN; 3]));
+        let palettes = SgbPalettes::from_packets(&data).unwrap();
+        assert_eq!(palettes.colors(0).unwrap()[1], [255, 0, 0]);
+        assert!(palettes.colors(2).is_none());
+    }
+
+    #[test]
+    fn rejects_partial_packet() {
+        let data = packet(0x00, WHITE, [RED; 3], [GREEN; 3]);
+        assert!(matches!(
+            SgbPalettes::from_packets(&data[..15]),
+            Err(Error::InvalidPacketData(15))
+        ));
+    }
+
+    #[test]
+    fn reads_hex_text() {
+        assert_eq!(
+            read_text("01 ff,7F\n0a").unwrap(),
+            vec![0x01, 0xFF, 0x7F, 0x0A]
+        );
+    }
+
+    #[test]
+    fn rejects_odd_hex_digits() {
+        assert!(matches!(read_text("01 ff 7"), Err(Error::OddHexDigits(5))));
+    }
+}
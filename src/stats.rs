@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use clap::ValueEnum;
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use tera::Context;
+
+use crate::image_handler::{self, ColorMode, ImageHandler, TEMPLATES};
+use crate::metadata::ScreenshotMetadata;
+use crate::palette::{ColorExt, Palette};
+
+#[derive(Debug, Copy, Clone, Default, ValueEnum)]
+pub enum StatsFormat {
+    #[default]
+    Table,
+    Json,
+    Html,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SlotUsage {
+    pub slot: String,
+    /// Color of slot in template, so charts look like screenshots
+    pub color: String,
+    pub pixels: usize,
+    pub percent: f32,
+    /// Percent of pixels of the most used slot, for charts
+    pub bar: f32,
+    /// Number of screenshots slot appears in
+    pub screenshots: usize,
+}
+
+/// How many times two slots were found next to each other (horizontally or vertically)
+#[derive(Debug, Clone, Serialize)]
+pub struct AdjacentSlots {
+    pub a: String,
+    pub b: String,
+    pub count: usize,
+}
+
+/// Slot usage of all screenshots of one game
+#[derive(Debug, Clone, Serialize)]
+pub struct GameStats {
+    pub game: String,
+    pub screenshots: usize,
+    pub pixels: usize,
+    /// Pixels not matching any template color
+    pub unknown: usize,
+    pub slots: Vec<SlotUsage>,
+    pub unused: Vec<String>,
+    /// Most often first
+    pub adjacent: Vec<AdjacentSlots>,
+}
+
+/// Palettized screenshot, reduced to what statistics need
+struct ScreenshotCounts {
+    counts: [usize; 256],
+    adjacent: BTreeMap<(u8, u8), usize>,
+}
+
+impl ScreenshotCounts {
+    fn new(template: &Palette, input_image: &str) -> Result<Self, image_handler::Error> {
+        let (width, _, indexes) =
+            ImageHandler::palettize_file(template, input_image, &ColorMode::Template, None, None)?;
+        let mut counts = [0; 256];
+        let mut adjacent = BTreeMap::new();
+        let mut pair = |a: u8, b: u8| {
+            if a != b && a != 255 && b != 255 {
+                *adjacent.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        };
+        for (position, index) in indexes.iter().enumerate() {
+            counts[*index as usize] += 1;
+            if (position + 1) % width != 0 {
+                pair(*index, indexes[position + 1]);
+            }
+            if let Some(below) = indexes.get(position + width) {
+                pair(*index, *below);
+            }
+        }
+        Ok(Self { counts, adjacent })
+    }
+}
+
+pub struct Stats;
+
+impl Stats {
+    /// Captured `{game}` part of file name, `*` matches anything. E.g. for `{game}_*.png`
+    /// `Tetris_001.png` gives `Tetris`.
+    ///
+    /// Capture is greedy: it is the longest one which still lets rest of pattern match,
+    /// as game names often contain the same separators, so `Super_Mario_Land_001.png`
+    /// gives `Super_Mario_Land`
+    pub fn game_from_pattern(pattern: &str, file_name: &str) -> Option<String> {
+        fn matches(pattern: &str, name: &str, game: &mut Option<String>) -> bool {
+            if let Some(rest) = pattern.strip_prefix("{game}") {
+                // Game name is not empty, take the longest one which lets rest match
+                return (1..=name.len())
+                    .rev()
+                    .filter(|end| name.is_char_boundary(*end))
+                    .any(|end| {
+                        let found = matches(rest, &name[end..], game);
+                        if found && game.is_none() {
+                            *game = Some(name[..end].to_string());
+                        }
+                        found
+                    });
+            }
+            if let Some(rest) = pattern.strip_prefix('*') {
+                return (0..=name.len())
+                    .filter(|start| name.is_char_boundary(*start))
+                    .any(|start| matches(rest, &name[start..], game));
+            }
+            match (pattern.chars().next(), name.chars().next()) {
+                (None, None) => true,
+                (Some(p), Some(n)) if p == n => {
+                    matches(&pattern[p.len_utf8()..], &name[n.len_utf8()..], game)
+                }
+                _ => false,
+            }
+        }
+        let mut game = None;
+        matches(pattern, file_name, &mut game)
+            .then_some(game)
+            .flatten()
+    }
+
+    /// Game of screenshot: from sidecar, then from file name pattern, otherwise `default`
+    fn game_of(input_image: &str, pattern: Option<&str>, default: &str) -> String {
+        ScreenshotMetadata::load(input_image)
+            .game
+            .or_else(|| {
+                let file_name = Path::new(input_image).file_name()?.to_string_lossy();
+                pattern.and_then(|pattern| Self::game_from_pattern(pattern, &file_name))
+            })
+            .unwrap_or_else(|| default.to_string())
+    }
+
+    /// Statistics of screenshots grouped by game, sorted by game name.
+    ///
+    /// Screenshots which cannot be read are skipped with warning
+    pub fn collect(
+        template: &Palette,
+        input_images: &[String],
+        pattern: Option<&str>,
+    ) -> Vec<GameStats> {
+        let groups = input_images
+            .iter()
+            .into_group_map_by(|input_image| Self::game_of(input_image, pattern, "all"));
+        groups
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .filter_map(|(game, input_images)| {
+                debug!("Screenshots of {game}: {input_images:?}");
+                let counts: Vec<ScreenshotCounts> = input_images
+                    .par_iter()
+                    .filter_map(|input_image| {
+                        ScreenshotCounts::new(template, input_image)
+                            .inspect_err(|err| warn!("{err}, skipping it"))
+                            .ok()
+                    })
+                    .collect();
+                (!counts.is_empty()).then(|| Self::game_stats(template, game, &counts))
+            })
+            .collect()
+    }
+
+    fn game_stats(template: &Palette, game: String, counts: &[ScreenshotCounts]) -> GameStats {
+        let slot_pixels = |slot: usize| counts.iter().map(|c| c.counts[slot]).sum::<usize>();
+        let unknown = slot_pixels(255);
+        let pixels = (0..Palette::SLOTS).map(slot_pixels).sum::<usize>() + unknown;
+        let most_used = (0..Palette::SLOTS).map(slot_pixels).max().unwrap_or(0);
+        let slots: Vec<SlotUsage> = (0..Palette::SLOTS)
+            .map(|slot| SlotUsage {
+                slot: Palette::slot_name(slot),
+                color: template.slot(slot).to_hex(),
+                pixels: slot_pixels(slot),
+                percent: slot_pixels(slot) as f32 / pixels.max(1) as f32 * 100.0,
+                bar: slot_pixels(slot) as f32 / most_used.max(1) as f32 * 100.0,
+                screenshots: counts.iter().filter(|c| c.counts[slot] > 0).count(),
+            })
+            .collect();
+        let unused = slots
+            .iter()
+            .filter(|usage| usage.pixels == 0)
+            .map(|usage| usage.slot.clone())
+            .collect();
+        let mut adjacent: BTreeMap<(u8, u8), usize> = BTreeMap::new();
+        for screenshot in counts {
+            for (pair, count) in &screenshot.adjacent {
+                *adjacent.entry(*pair).or_insert(0) += count;
+            }
+        }
+        let adjacent = adjacent
+            .into_iter()
+            .sorted_by(|(_, a), (_, b)| b.cmp(a))
+            .map(|((a, b), count)| AdjacentSlots {
+                a: Palette::slot_name(a as usize),
+                b: Palette::slot_name(b as usize),
+                count,
+            })
+            .collect();
+        GameStats {
+            game,
+            screenshots: counts.len(),
+            pixels,
+            unknown,
+            slots,
+            unused,
+            adjacent,
+        }
+    }
+
+    /// Human readable table, only most common adjacent pairs are listed
+    pub fn table(stats: &[GameStats]) -> String {
+        const ADJACENT_SHOWN: usize = 10;
+        let mut table = String::new();
+        for game in stats {
+            table += &format!(
+                "== {} ({} screenshot(s), {} pixels) ==\n",
+                game.game, game.screenshots, game.pixels
+            );
+            table += &format!(
+                "{:<10} {:>10} {:>8} {:>12}\n",
+                "slot", "pixels", "%", "screenshots"
+            );
+            for usage in &game.slots {
+                table += &format!(
+                    "{:<10} {:>10} {:>7.2}% {:>12}\n",
+                    usage.slot, usage.pixels, usage.percent, usage.screenshots
+                );
+            }
+            if game.unknown > 0 {
+                table += &format!("{:<10} {:>10}\n", "unknown", game.unknown);
+            }
+            table += &format!(
+                "Never used: {}\n",
+                if game.unused.is_empty() {
+                    "-".to_string()
+                } else {
+                    game.unused.join(", ")
+                }
+            );
+            table += "Most common adjacent slots:\n";
+            for pair in game.adjacent.iter().take(ADJACENT_SHOWN) {
+                table += &format!("  {:<10} {:<10} {:>10}\n", pair.a, pair.b, pair.count);
+            }
+        }
+        table
+    }
+
+    /// Write statistics in given format, or log them if there is no output file
+    pub fn write(stats: &[GameStats], format: StatsFormat, output_file: Option<&str>) {
+        let text = match format {
+            StatsFormat::Table => Self::table(stats),
+            StatsFormat::Json => {
+                serde_json::to_string_pretty(stats).expect("Cannot serialize statistics")
+            }
+            StatsFormat::Html => {
+                let mut context = Context::new();
+                context.insert("version", env!("GIT_HASH_SHORT"));
+                context.insert("games", stats);
+                TEMPLATES
+                    .render("stats.html", &context)
+                    .unwrap_or_else(|err| panic!("Cannot render statistics: {err}"))
+            }
+        };
+        match output_file {
+            Some(output_file) => {
+                fs::write(output_file, text)
+                    .unwrap_or_else(|err| panic!("Cannot write {output_file}: {err}"));
+                info!("Statistics written to {output_file}");
+            }
+            None => info!("Slot statistics:\n{text}"),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::env;
+use std::fmt::Write;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::ValueEnum;
+use colored::Colorize;
+use itertools::Itertools;
+use log::{debug, info};
+
+use crate::helpers::Helpers;
+use crate::image_handler::ImageHandler;
+use crate::palette::{Color, Palette};
+use crate::png_helper::{PngHelper, PngPalette};
+
+/// Way of displaying images inside terminal
+#[derive(Debug, Copy, Clone, Default, PartialEq, ValueEnum)]
+pub enum GraphicsProtocol {
+    /// Guess best protocol using environment variables set by terminal emulator
+    #[default]
+    Auto,
+    /// Kitty graphics protocol, also supported by e.g. Ghostty and WezTerm
+    Kitty,
+    /// iTerm2 inline images protocol, also supported by e.g. WezTerm
+    #[clap(alias = "iterm")]
+    Iterm2,
+    /// DEC Sixel graphics, supported by e.g. foot, mlterm, xterm (when enabled)
+    Sixel,
+    /// Unicode half-block characters with 24-bit colors, works almost everywhere
+    #[clap(alias = "blocks")]
+    HalfBlocks,
+}
+
+pub struct TerminalPreview;
+
+impl TerminalPreview {
+    /// Kitty protocol allows at most 4096 bytes of base64 data per escape sequence
+    const KITTY_CHUNK_SIZE: usize = 4096;
+
+    /// Guess which graphics protocol terminal supports.
+    ///
+    /// There is no reliable way to do it without querying terminal and waiting for response,
+    /// so just look at environment variables commonly set by terminal emulators.
+    pub fn detect() -> GraphicsProtocol {
+        let var = |name: &str| env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let term_program = var("TERM_PROGRAM");
+        let protocol = if env::var("KITTY_WINDOW_ID").is_ok()
+            || term == "xterm-kitty"
+            || term == "xterm-ghostty"
+            || term_program == "ghostty"
+        {
+            GraphicsProtocol::Kitty
+        } else if term_program == "iTerm.app"
+            || term_program == "WezTerm"
+            || var("LC_TERMINAL") == "iTerm2"
+        {
+            GraphicsProtocol::Iterm2
+        } else if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") {
+            GraphicsProtocol::Sixel
+        } else {
+            GraphicsProtocol::HalfBlocks
+        };
+        debug!("Detected graphics protocol {protocol:?} (TERM='{term}', TERM_PROGRAM='{term_program}')");
+        protocol
+    }
+
+    /// Render paletted image as string which can be printed directly to terminal
+    ///
+    /// `palette` is PLTE-like array of RGB triplets, `data` are palette indexes
+    pub fn render(
+        protocol: GraphicsProtocol,
+        width: usize,
+        height: usize,
+        palette: &[u8],
+        data: &[u8],
+    ) -> String {
+        match protocol {
+            GraphicsProtocol::Auto => Self::render(Self::detect(), width, height, palette, data),
+            GraphicsProtocol::Kitty => Self::kitty(width, height, palette, data),
+            GraphicsProtocol::Iterm2 => Self::iterm2(width, height, palette, data),
+            GraphicsProtocol::Sixel => Self::sixel(width, height, palette, data),
+            GraphicsProtocol::HalfBlocks => {
+                Self::half_blocks(width, height, palette, data).join("\n")
+            }
+        }
+    }
+
+    fn kitty(width: usize, height: usize, palette: &[u8], data: &[u8]) -> String {
+        let png = PngHelper::encode(width as u32, height as u32, palette, data);
+        let encoded = BASE64.encode(png);
+        let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(Self::KITTY_CHUNK_SIZE).collect();
+        let mut output = String::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            // base64 output is always valid ASCII
+            let chunk = std::str::from_utf8(chunk).unwrap();
+            let more = if i + 1 < chunks.len() { 1 } else { 0 };
+            if i == 0 {
+                // a=T - transmit and display, f=100 - PNG data, q=2 - do not send any responses
+                write!(output, "\x1b_Ga=T,f=100,q=2,m={more};{chunk}\x1b\\").unwrap();
+            } else {
+                write!(output, "\x1b_Gm={more};{chunk}\x1b\\").unwrap();
+            }
+        }
+        output.push('\n');
+        output
+    }
+
+    fn iterm2(width: usize, height: usize, palette: &[u8], data: &[u8]) -> String {
+        let png = PngHelper::encode(width as u32, height as u32, palette, data);
+        format!(
+            "\x1b]1337;File=inline=1;size={};preserveAspectRatio=1:{}\x07\n",
+            png.len(),
+            BASE64.encode(&png)
+        )
+    }
+
+    /// Sixel maps really nicely to paletted images - each palette index becomes color register
+    fn sixel(width: usize, height: usize, palette: &[u8], data: &[u8]) -> String {
+        let percent = |value: u8| (value as u32 * 100 + 127) / 255;
+        let mut used = [false; 256];
+        data.iter().for_each(|index| used[*index as usize] = true);
+
+        // P2 = 1 - pixels not drawn stay transparent, raster attributes force 1:1 pixel aspect ratio
+        let mut output = format!("\x1bP0;1q\"1;1;{width};{height}");
+        for (index, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+            let color = &palette[index * 3..index * 3 + 3];
+            write!(
+                output,
+                "#{index};2;{};{};{}",
+                percent(color[0]),
+                percent(color[1]),
+                percent(color[2])
+            )
+            .unwrap();
+        }
+
+        // Each sixel character encodes column of 6 pixels for single color
+        for band_start in (0..height).step_by(6) {
+            let band_end = (band_start + 6).min(height);
+            let mut first_color = true;
+            for (index, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+                let bits: Vec<u8> = (0..width)
+                    .map(|x| {
+                        (band_start..band_end)
+                            .filter(|y| data[y * width + x] as usize == index)
+                            .fold(0_u8, |bits, y| bits | 1 << (y - band_start))
+                    })
+                    .collect();
+                if bits.iter().all(|b| *b == 0) {
+                    continue;
+                }
+                if !first_color {
+                    // go back to start of the band and paint another color
+                    output.push('$');
+                }
+                first_color = false;
+                write!(output, "#{index}").unwrap();
+                for (run, bits) in bits.iter().dedup_with_count() {
+                    let character = (63 + bits) as char;
+                    if run > 3 {
+                        write!(output, "!{run}{character}").unwrap();
+                    } else {
+                        (0..run).for_each(|_| output.push(character));
+                    }
+                }
+            }
+            output.push('-');
+        }
+        output.push_str("\x1b\\\n");
+        output
+    }
+
+    /// Render image using upper half block characters, so each character cell displays 2 pixels:
+    /// top one as foreground, bottom one as background.
+    ///
+    /// Returned as separate lines, so caller can position them wherever needed
+    pub fn half_blocks(width: usize, height: usize, palette: &[u8], data: &[u8]) -> Vec<String> {
+        let color_at = |x: usize, y: usize| -> Color {
+            let index = data[y * width + x] as usize;
+            palette[index * 3..index * 3 + 3].try_into().unwrap()
+        };
+        (0..height)
+            .step_by(2)
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let top = color_at(x, y);
+                        let block = "▀".truecolor(top[0], top[1], top[2]);
+                        if y + 1 < height {
+                            let bottom = color_at(x, y + 1);
+                            block
+                                .on_truecolor(bottom[0], bottom[1], bottom[2])
+                                .to_string()
+                        } else {
+                            block.to_string()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Colorize screenshots using palette and print them directly to terminal
+    pub fn preview(
+        pal_file: &str,
+        input_images: &[String],
+        output_scale: Option<u8>,
+        protocol: GraphicsProtocol,
+        template: &Palette,
+    ) {
+        let palette =
+            Palette::load(pal_file).unwrap_or_else(|err| panic!("Cannot load palette: {err}"));
+        let pal: PngPalette = palette.into();
+        let pal: [u8; 256 * 3] = pal.into();
+        let protocol = if protocol == GraphicsProtocol::Auto {
+            Self::detect()
+        } else {
+            protocol
+        };
+        let output_scale = output_scale.unwrap_or(1) as usize;
+        for input_image in Helpers::glob_paths(input_images) {
+            let image = ImageHandler::load_image(&input_image);
+            let (width, height) = (image.width() as usize, image.height() as usize);
+            let unscaled = ImageHandler::palettize_image(template.clone(), &image);
+            let scaled = ImageHandler::scale_paletted_image(&unscaled, width, height, output_scale);
+            info!("Previewing {} using {:?}", input_image, protocol);
+            println!("{}", input_image.bold());
+            print!(
+                "{}",
+                Self::render(
+                    protocol,
+                    width * output_scale,
+                    height * output_scale,
+                    &pal,
+                    &scaled
+                )
+            );
+            if protocol == GraphicsProtocol::HalfBlocks {
+                println!();
+            }
+        }
+    }
+}
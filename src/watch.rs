@@ -0,0 +1,323 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use log::{debug, info, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::gallery::Gallery;
+use crate::helpers::Helpers;
+use crate::image_handler::{ColorizeOptions, ImageHandler};
+use crate::library::Library;
+use crate::naming::Placeholder;
+
+/// Re-renders colorized images whenever palettes or screenshots change
+pub struct Watch {
+    pal_files: Vec<String>,
+    input_images: Vec<String>,
+    output_image_file: String,
+    options: ColorizeOptions,
+    /// Outputs go to gallery folders, see [`Gallery::jobs`]
+    gallery: Option<Gallery>,
+    /// Files written by us, changes to them are ignored and they are never used as inputs
+    outputs: HashSet<PathBuf>,
+}
+
+impl Watch {
+    /// Wait until there were no new changes for this long before rebuilding,
+    /// so e.g. copying whole folder of palettes triggers only one rebuild
+    const DEBOUNCE: Duration = Duration::from_millis(500);
+
+    pub fn new(
+        pal_files: &[String],
+        input_images: &[String],
+        output_image_file: &str,
+        options: &ColorizeOptions,
+        gallery: Option<Gallery>,
+    ) -> Self {
+        Self {
+            pal_files: pal_files.to_vec(),
+            input_images: input_images.to_vec(),
+            output_image_file: output_image_file.to_string(),
+            options: options.clone(),
+            gallery,
+            outputs: HashSet::new(),
+        }
+    }
+
+    /// Build everything once, then keep watching for changes. Never returns.
+    pub fn run(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).expect("Cannot create file system watcher");
+        let directories: HashSet<PathBuf> = self
+            .pal_files
+            .iter()
+            .chain(&self.input_images)
+            .filter(|pattern| !Library::is_builtin(pattern))
+            .map(|pattern| Helpers::glob_base_dir(pattern))
+            .collect();
+        for directory in &directories {
+            watcher
+                .watch(directory, RecursiveMode::Recursive)
+                .unwrap_or_else(|err| panic!("Cannot watch directory {directory:?}: {err}"));
+        }
+
+        let (mut palettes, mut images) = self.glob();
+        self.rebuild(&palettes, &images, None);
+        self.html(&palettes);
+        info!("Watching {directories:?} for changes, press Ctrl+C to stop");
+
+        loop {
+            let changed = self.wait_for_changes(&receiver);
+            let start = Instant::now();
+            let (new_palettes, new_images) = self.glob();
+            let lists_changed = new_palettes != palettes || new_images != images;
+            let written = if lists_changed {
+                Self::log_list_changes("palette", &palettes, &new_palettes);
+                Self::log_list_changes("screenshot", &images, &new_images);
+                let written = self.rebuild_added(&palettes, &images, &new_palettes, &new_images);
+                (palettes, images) = (new_palettes, new_images);
+                written
+            } else {
+                let changed_images: HashSet<String> = images
+                    .iter()
+                    .filter(|image| changed.contains(&Self::normalize(Path::new(image))))
+                    .cloned()
+                    .collect();
+                let changed_palettes: Vec<String> = palettes
+                    .iter()
+                    .filter(|palette| changed.contains(&Self::normalize(Path::new(palette))))
+                    .cloned()
+                    .collect();
+                let mut written = Vec::new();
+                if !changed_images.is_empty() {
+                    info!("Screenshot(s) changed: {changed_images:?}");
+                    written.extend(self.rebuild(&palettes, &images, Some(&changed_images)));
+                }
+                if !changed_palettes.is_empty() {
+                    info!("Palette(s) changed: {changed_palettes:?}");
+                    if changed_images.is_empty() || self.options.merge {
+                        written.extend(self.rebuild(&changed_palettes, &images, None));
+                    } else {
+                        // Changed screenshots were already rebuilt with all palettes above
+                        let unchanged: HashSet<String> =
+                            images.difference(&changed_images).cloned().collect();
+                        written.extend(self.rebuild(&changed_palettes, &images, Some(&unchanged)));
+                    }
+                }
+                written
+            };
+            if lists_changed || !written.is_empty() {
+                self.html(&palettes);
+            }
+            if written.is_empty() {
+                debug!("Nothing to rebuild");
+            } else {
+                info!(
+                    "Rebuilt {} file(s) in {:.2?}: {:?}",
+                    written.len(),
+                    start.elapsed(),
+                    written
+                );
+            }
+        }
+    }
+
+    /// Rebuild after palettes or screenshots were added or removed: only outputs of new ones,
+    /// unless output names of others change too, or all screenshots are merged
+    fn rebuild_added(
+        &mut self,
+        old_palettes: &[String],
+        old_images: &HashSet<String>,
+        palettes: &[String],
+        images: &HashSet<String>,
+    ) -> Vec<String> {
+        let added_palettes: Vec<String> = palettes
+            .iter()
+            .filter(|palette| !old_palettes.contains(palette))
+            .cloned()
+            .collect();
+        let kept_palettes: Vec<String> = palettes
+            .iter()
+            .filter(|palette| old_palettes.contains(palette))
+            .cloned()
+            .collect();
+        let added_images: HashSet<String> = images.difference(old_images).cloned().collect();
+        let images_changed = images != old_images;
+        // Without name template, outputs are named by counter of screenshot,
+        // and by palette only if there are more of them
+        let counter_names = match &self.options.name_template {
+            Some(name_template) => name_template.uses(Placeholder::Counter),
+            None => true,
+        };
+        let palette_names_changed = self.options.name_template.is_none()
+            && (old_palettes.len() == 1) != (palettes.len() == 1);
+        if palette_names_changed || (images_changed && (self.options.merge || counter_names)) {
+            return self.rebuild(palettes, images, None);
+        }
+        let mut written = Vec::new();
+        if !added_images.is_empty() {
+            written.extend(self.rebuild(&kept_palettes, images, Some(&added_images)));
+        }
+        if !added_palettes.is_empty() {
+            written.extend(self.rebuild(&added_palettes, images, None));
+        }
+        written
+    }
+
+    /// Currently matching palettes and screenshots, excluding anything we have written
+    fn glob(&self) -> (Vec<String>, HashSet<String>) {
+        let palettes = Helpers::glob_paths(&self.pal_files);
+        let images = Helpers::glob_paths(&self.input_images)
+            .into_iter()
+            .filter(|image| !self.outputs.contains(&Self::normalize(Path::new(image))))
+            .collect();
+        (palettes, images)
+    }
+
+    fn rebuild(
+        &mut self,
+        palettes: &[String],
+        images: &HashSet<String>,
+        only_images: Option<&HashSet<String>>,
+    ) -> Vec<String> {
+        if palettes.is_empty() || images.is_empty() {
+            warn!("No palettes or screenshots found, nothing to do");
+            return Vec::new();
+        }
+        if let Some(cache) = &self.options.cache {
+            cache.forget_file_hashes();
+        }
+        let palettes_count = Helpers::glob_paths(&self.pal_files).len();
+        let images: Vec<String> = images.iter().cloned().collect();
+        let jobs = self.jobs(palettes, palettes_count);
+        // Keep watching, problem may be fixed by next change
+        let written = match ImageHandler::color_images_with_palettes(
+            &jobs,
+            &images,
+            &self.options,
+            only_images,
+        ) {
+            Ok(written) => written,
+            Err(err) => {
+                warn!("{err}");
+                return Vec::new();
+            }
+        };
+        self.outputs
+            .extend(written.iter().map(|file| Self::normalize(Path::new(file))));
+        written
+    }
+
+    /// Output file of each palette, as in [`ImageHandler::use_palettes_to_color_images`]
+    /// or [`Gallery::colorize`]
+    fn jobs(&self, palettes: &[String], palettes_count: usize) -> Vec<(String, String)> {
+        if let Some(gallery) = &self.gallery {
+            let mut jobs = gallery.jobs(&self.pal_files);
+            jobs.retain(|(pal, _)| palettes.contains(pal));
+            return jobs;
+        }
+        palettes
+            .iter()
+            .map(|pal| {
+                let output_image_file = ImageHandler::output_file_for_palette(
+                    &self.options,
+                    &self.output_image_file,
+                    pal,
+                    palettes_count,
+                );
+                (pal.clone(), output_image_file)
+            })
+            .collect()
+    }
+
+    /// Same as in [`ImageHandler::use_palettes_to_color_images`], HTML is generated only for multiple palettes
+    fn html(&self, palettes: &[String]) {
+        if !self.options.generate_html || palettes.len() < 2 {
+            return;
+        }
+        let pal_images = self.jobs(palettes, palettes.len());
+        let images: Vec<String> = self.glob().1.into_iter().sorted().collect();
+        ImageHandler::generate_html(&ImageHandler::html_images(
+            &pal_images,
+            &images,
+            &self.options,
+        ));
+    }
+
+    /// Whether changed path may be one of inputs: palette or screenshot, but not our output
+    /// or log file (which is written on every change, so it would trigger itself forever)
+    fn is_input(&self, path: &Path, log_file: &Path) -> bool {
+        let extension = path.extension().unwrap_or_default();
+        (extension.eq_ignore_ascii_case("pal") || extension.eq_ignore_ascii_case("png"))
+            && path != log_file
+            && !self.outputs.contains(path)
+    }
+
+    /// Block until something changes, then collect all changes until they stop for a moment.
+    ///
+    /// Changes of other files than inputs are ignored
+    fn wait_for_changes(&self, receiver: &Receiver<notify::Result<Event>>) -> HashSet<PathBuf> {
+        let log_file = Self::normalize(Path::new(Helpers::LOG_FILE));
+        loop {
+            let changed = Self::collect_changes(receiver);
+            let changed: HashSet<PathBuf> = changed
+                .into_iter()
+                .filter(|path| self.is_input(path, &log_file))
+                .collect();
+            if !changed.is_empty() {
+                debug!("Changed paths: {changed:?}");
+                return changed;
+            }
+        }
+    }
+
+    fn collect_changes(receiver: &Receiver<notify::Result<Event>>) -> HashSet<PathBuf> {
+        let mut changed = HashSet::new();
+        let mut collect = |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    changed.extend(event.paths.iter().map(|path| Self::normalize(path)));
+                }
+            }
+            Err(err) => warn!("File system watcher error: {err}"),
+        };
+        collect(receiver.recv().expect("File system watcher stopped"));
+        while let Ok(event) = receiver.recv_timeout(Self::DEBOUNCE) {
+            collect(event);
+        }
+        changed
+    }
+
+    /// Canonical path, so paths from globs and from watcher events can be compared.
+    ///
+    /// Removed files cannot be canonicalized, so for these only parent directory is canonicalized
+    fn normalize(path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => fs::canonicalize(parent)
+                .map(|parent| parent.join(file_name))
+                .unwrap_or_else(|_| path.to_path_buf()),
+            _ => path.to_path_buf(),
+        })
+    }
+
+    fn log_list_changes<T: AsRef<str>>(
+        kind: &str,
+        old: impl IntoIterator<Item = T>,
+        new: impl IntoIterator<Item = T>,
+    ) {
+        let old: HashSet<String> = old.into_iter().map(|s| s.as_ref().to_string()).collect();
+        let new: HashSet<String> = new.into_iter().map(|s| s.as_ref().to_string()).collect();
+        new.difference(&old)
+            .for_each(|path| info!("New {kind} found: {path}"));
+        old.difference(&new)
+            .for_each(|path| info!("{kind} removed: {path}"));
+    }
+}